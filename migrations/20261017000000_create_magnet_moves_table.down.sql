@@ -0,0 +1,3 @@
+DROP TRIGGER IF EXISTS magnet_moved ON magnets;
+DROP FUNCTION IF EXISTS record_move;
+DROP TABLE IF EXISTS magnet_moves;
@@ -0,0 +1,30 @@
+CREATE TABLE IF NOT EXISTS magnet_moves (
+    id BIGSERIAL PRIMARY KEY,
+    magnet_id INTEGER NOT NULL REFERENCES magnets (id),
+    old_coords POINT NOT NULL,
+    new_coords POINT NOT NULL,
+    old_rotation INTEGER NOT NULL,
+    new_rotation INTEGER NOT NULL,
+    old_z_index BIGINT NOT NULL,
+    new_z_index BIGINT NOT NULL,
+    modifier UUID,
+    moved_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS idx_magnet_moves_moved_at ON magnet_moves (moved_at);
+CREATE INDEX IF NOT EXISTS idx_magnet_moves_magnet_id ON magnet_moves (magnet_id, moved_at);
+
+CREATE OR REPLACE FUNCTION record_move() RETURNS TRIGGER AS $$
+  BEGIN
+    INSERT INTO magnet_moves (
+      magnet_id, old_coords, new_coords, old_rotation, new_rotation, old_z_index, new_z_index, modifier
+    ) VALUES (
+      NEW.id, OLD.coords, NEW.coords, OLD.rotation, NEW.rotation, OLD.z_index, NEW.z_index, NEW.last_modifier
+    );
+    RETURN NULL;
+  END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER magnet_moved
+  AFTER UPDATE ON magnets
+  FOR EACH ROW EXECUTE PROCEDURE record_move();
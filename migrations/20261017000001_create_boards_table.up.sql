@@ -0,0 +1,42 @@
+CREATE TABLE IF NOT EXISTS boards (
+    id TEXT PRIMARY KEY,
+    min_x INTEGER NOT NULL,
+    min_y INTEGER NOT NULL,
+    max_x INTEGER NOT NULL,
+    max_y INTEGER NOT NULL
+);
+
+INSERT INTO boards (id, min_x, min_y, max_x, max_y)
+VALUES ('default', -500000, -500000, 500000, 500000)
+ON CONFLICT DO NOTHING;
+
+-- A constant default doesn't rewrite the table. The foreign key is only checked
+-- for new rows here, existing ones are checked by a later migration that
+-- doesn't block writes while it scans.
+ALTER TABLE magnets ADD COLUMN IF NOT EXISTS board_id TEXT NOT NULL DEFAULT 'default';
+ALTER TABLE magnets
+    ADD CONSTRAINT magnets_board_id_fkey FOREIGN KEY (board_id) REFERENCES boards (id) NOT VALID;
+
+-- Magnets are still found through idx_magnets_coords, with board_id as a filter.
+-- A busy board sharing coordinates with others can be given its own index:
+-- CREATE INDEX CONCURRENTLY ... ON magnets USING gist (coords) WHERE board_id = '...';
+
+CREATE OR REPLACE FUNCTION notify_change() RETURNS TRIGGER AS $$
+  DECLARE
+    payload TEXT;
+  BEGIN
+    payload := json_build_object(
+      'id', NEW.id,
+      'board_id', NEW.board_id,
+      'old_x', OLD.coords[0],
+      'old_y', OLD.coords[1],
+      'new_x', NEW.coords[0],
+      'new_y', NEW.coords[1],
+      'rotation', NEW.rotation,
+      'z_index', NEW.z_index,
+      'word', NEW.word
+    );
+    PERFORM pg_notify('magnet_updates', payload);
+    RETURN NULL;
+  END;
+$$ LANGUAGE plpgsql;
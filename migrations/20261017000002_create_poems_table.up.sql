@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS poems (
+    slug TEXT PRIMARY KEY,
+    board_id TEXT NOT NULL REFERENCES boards (id),
+    min_x INTEGER NOT NULL,
+    min_y INTEGER NOT NULL,
+    max_x INTEGER NOT NULL,
+    max_y INTEGER NOT NULL,
+    magnets JSONB NOT NULL,
+    created_by UUID,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
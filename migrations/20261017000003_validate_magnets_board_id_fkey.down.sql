@@ -0,0 +1,2 @@
+-- A validated constraint can't be marked NOT VALID again, and it's dropped
+-- along with board_id when the boards table goes.
@@ -0,0 +1,3 @@
+-- Only takes a SHARE UPDATE EXCLUSIVE lock, so magnets can still be moved
+-- while existing rows are checked.
+ALTER TABLE magnets VALIDATE CONSTRAINT magnets_board_id_fkey;
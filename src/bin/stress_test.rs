@@ -1,3 +1,15 @@
+//! Connects many clients to a server on `localhost:8080`, each moving its
+//! window around and moving magnets in it, and reports response latencies.
+//! A response is the canvas for a window update or the echo of a move, and
+//! requests that get none within a second count as taking a second.
+//!
+//! All of the clients connect from 127.0.0.1, so the server counts them as a
+//! single peer sharing one request budget. Start the server with
+//! `FRIDGE_SESSIONS_PER_IP` at least as high as `CLIENTS`, or most clients
+//! will be rate limited and disconnected instead of measured.
+
+use std::time::{Duration, Instant};
+
 use futures_util::{SinkExt as _, StreamExt as _};
 use rand::{Rng as _, SeedableRng, seq::IndexedRandom as _};
 use serde::{Deserialize, Serialize};
@@ -29,17 +41,62 @@ enum ClientUpdate {
 #[derive(Debug, Deserialize)]
 struct Magnet {
     id: i32,
-    x: i32,
-    y: i32,
+    _x: i32,
+    _y: i32,
     _rotation: i32,
     _z_index: i64,
     _word: String,
 }
 
+/// A legacy move, as sent back to everyone looking at the magnet
+#[derive(Debug, Deserialize)]
+struct Location {
+    id: i32,
+    _x: i32,
+    _y: i32,
+    _rotation: i32,
+    _z_index: i64,
+}
+
+/// The response a client is waiting for
+#[derive(Clone, Copy, Debug)]
+enum Expected {
+    /// The magnets in the new window
+    Window,
+    /// The move of this magnet coming back
+    Move(i32),
+}
+
+const CLIENTS: usize = 1_000;
+const ITERATIONS: usize = 200;
+
+/// What one client saw over the course of the test.
+#[derive(Debug, Default)]
+struct ClientStats {
+    messages: usize,
+    /// Whether the server hung up before the client was done
+    disconnected: bool,
+    /// Requests that got no response within a second
+    timeouts: usize,
+    /// Time from sending a request to receiving its response, or to giving up
+    /// on it, so that dropped responses count against the percentiles
+    latencies: Vec<Duration>,
+}
+
 #[tokio::main]
 async fn main() {
-    for _ in 0..1_000 {
-        tokio::spawn(async {
+    println!(
+        "Connecting {CLIENTS} clients from this machine, make sure the server is running with \
+         FRIDGE_SESSIONS_PER_IP={CLIENTS} or more"
+    );
+
+    let start = Instant::now();
+    let mut clients = Vec::with_capacity(CLIENTS);
+
+    for _ in 0..CLIENTS {
+        clients.push(tokio::spawn(async {
+            let mut stats = ClientStats::default();
+
             let mut rng = rand::rngs::SmallRng::from_os_rng();
             let (mut client, response) = tokio_websockets::ClientBuilder::from_uri(
                 "ws://localhost:8080/ws".try_into().unwrap(),
@@ -57,8 +114,9 @@ async fn main() {
 
             tracing::debug!("starting to send stuff");
 
-            for _ in 0..200 {
-                if window {
+            for _ in 0..ITERATIONS {
+                let sent_at = Instant::now();
+                let expected = if window {
                     let x_diff = rng.random_range(-1000..1000);
                     let y_diff = rng.random_range(-1000..1000);
                     x_center += x_diff;
@@ -75,19 +133,25 @@ async fn main() {
                         x2: x_center + 1000,
                         y2: y_center + 1000,
                     });
-                    client
+                    if client
                         .send(tokio_websockets::Message::binary(
                             rmp_serde::to_vec(&update_message).unwrap(),
                         ))
                         .await
-                        .unwrap();
+                        .is_err()
+                    {
+                        stats.disconnected = true;
+                        break;
+                    }
+                    Some(Expected::Window)
                 } else {
                     let magnet = magnetsi.choose(&mut rng);
 
                     if let Some(magnet) = magnet {
                         let id = magnet.id;
-                        let x = magnet.x + rng.random_range(-1000..=1000);
-                        let y = magnet.y + rng.random_range(-1000..=1000);
+                        // The server only accepts moves to within the current window
+                        let x = x_center + rng.random_range(-1000..=1000);
+                        let y = y_center + rng.random_range(-1000..=1000);
                         let rotation = rng.random_range(-359..359);
 
                         let update_message = ClientUpdate::Magnet(ClientMagnetUpdate {
@@ -97,28 +161,108 @@ async fn main() {
                             y,
                             rotation,
                         });
-                        client
+                        if client
                             .send(tokio_websockets::Message::binary(
                                 rmp_serde::to_vec(&update_message).unwrap(),
                             ))
                             .await
-                            .unwrap();
+                            .is_err()
+                        {
+                            stats.disconnected = true;
+                            break;
+                        }
+                        Some(Expected::Move(id))
+                    } else {
+                        None
                     }
-                }
+                };
 
-                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
                 window = !window;
 
-                if let Some(Ok(message)) = client.next().await {
-                    assert!(message.is_binary());
-                    let client_update =
-                        rmp_serde::from_slice::<Vec<Magnet>>(&message.into_payload());
-                    if let Ok(magnets) = client_update {
-                        magnetsi = magnets;
+                // Wait for the response to this request specifically, counting
+                // everything else the server pushes in the meantime
+                let deadline = tokio::time::Instant::from_std(sent_at + Duration::from_secs(1));
+                if let Some(expected) = expected {
+                    loop {
+                        match tokio::time::timeout_at(deadline, client.next()).await {
+                            Ok(Some(Ok(message))) if message.is_binary() => {
+                                stats.messages += 1;
+                                let payload = message.into_payload();
+                                let responded = match expected {
+                                    Expected::Window => {
+                                        rmp_serde::from_slice::<Vec<Magnet>>(&payload)
+                                            .map(|magnets| magnetsi = magnets)
+                                            .is_ok()
+                                    }
+                                    Expected::Move(id) => {
+                                        rmp_serde::from_slice::<Location>(&payload)
+                                            .is_ok_and(|location| location.id == id)
+                                    }
+                                };
+                                if responded {
+                                    stats.latencies.push(sent_at.elapsed());
+                                    break;
+                                }
+                            }
+                            Ok(Some(Ok(_))) => {}
+                            Err(_) => {
+                                stats.timeouts += 1;
+                                stats.latencies.push(sent_at.elapsed());
+                                break;
+                            }
+                            Ok(Some(Err(_)) | None) => {
+                                stats.disconnected = true;
+                                break;
+                            }
+                        }
                     }
                 }
+                if stats.disconnected {
+                    break;
+                }
+
+                // Stay under the server's rate limit
+                tokio::time::sleep(Duration::from_millis(250).saturating_sub(sent_at.elapsed()))
+                    .await;
             }
-        });
-        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+            stats
+        }));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let mut messages = 0;
+    let mut disconnected = 0;
+    let mut timeouts = 0;
+    let mut latencies = Vec::new();
+    for client in clients {
+        let stats = client.await.unwrap();
+        messages += stats.messages;
+        disconnected += usize::from(stats.disconnected);
+        timeouts += stats.timeouts;
+        latencies.extend(stats.latencies);
+    }
+    latencies.sort();
+
+    let percentile = |p: usize| {
+        latencies
+            .get(latencies.len() * p / 100)
+            .copied()
+            .unwrap_or_default()
+    };
+    println!(
+        "{CLIENTS} clients ({disconnected} disconnected), {messages} messages, {} responses and \
+         {timeouts} timeouts in {:.1?}: p50 {:.1?}, p90 {:.1?}, p99 {:.1?}",
+        latencies.len() - timeouts,
+        start.elapsed(),
+        percentile(50),
+        percentile(90),
+        percentile(99)
+    );
+    if disconnected > 0 {
+        println!(
+            "Disconnected clients were probably rate limited, check that FRIDGE_SESSIONS_PER_IP \
+             is at least {CLIENTS}"
+        );
     }
 }
@@ -0,0 +1,124 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    geometry::Window, leases::Leases, state::PgMagnetUpdate, storage::Storage, tiles::TileRegistry,
+};
+
+pub const DEFAULT_BOARD: &str = "default";
+
+/// One fridge door, with its own magnets and its own stream of updates.
+#[derive(Debug)]
+pub struct Board {
+    pub id: String,
+    /// Area magnets on this board can be placed in
+    pub bounds: Window,
+    pub subscriptions: TileRegistry,
+    pub leases: Leases,
+}
+
+impl Board {
+    /// Gets the board named by the path of an upgrade request, either `/ws`
+    /// for the default board or `/ws/{board}`.
+    pub fn id_from_path(path: &str) -> Option<&str> {
+        match path.trim_end_matches('/') {
+            "/ws" => Some(DEFAULT_BOARD),
+            path => path
+                .strip_prefix("/ws/")
+                .filter(|id| !id.is_empty() && !id.contains('/')),
+        }
+    }
+}
+
+/// Boards that have been connected to since startup, loaded from Postgres on
+/// first use.
+#[derive(Clone, Debug)]
+pub struct Boards {
+    boards: Arc<Mutex<HashMap<String, Arc<Board>>>>,
+    broadcast_capacity: usize,
+}
+
+impl Boards {
+    pub fn new(broadcast_capacity: usize) -> Boards {
+        Boards {
+            boards: Default::default(),
+            broadcast_capacity,
+        }
+    }
+
+    #[tracing::instrument(skip(self, storage))]
+    pub async fn get(
+        &self,
+        id: &str,
+        storage: &dyn Storage,
+    ) -> Result<Option<Arc<Board>>, sqlx::Error> {
+        if let Some(board) = self.boards.lock().unwrap().get(id) {
+            return Ok(Some(board.clone()));
+        }
+
+        let Some(bounds) = storage.board_bounds(id).await? else {
+            return Ok(None);
+        };
+
+        // Someone else may have loaded the board while we were querying
+        let board = self
+            .boards
+            .lock()
+            .unwrap()
+            .entry(id.to_string())
+            .or_insert_with(|| {
+                Arc::new(Board {
+                    id: id.to_string(),
+                    bounds,
+                    subscriptions: TileRegistry::new(self.broadcast_capacity),
+                    leases: Leases::default(),
+                })
+            })
+            .clone();
+
+        Ok(Some(board))
+    }
+
+    /// Passes an update on to the sessions on its board that can see it.
+    pub fn broadcast(&self, magnet_update: PgMagnetUpdate) {
+        let boards = self.boards.lock().unwrap();
+        let Some(board) = boards.get(&magnet_update.board_id) else {
+            tracing::trace!("Nobody has connected to board {}", magnet_update.board_id);
+            return;
+        };
+
+        tracing::trace!("Propagating magnet update to websocket tasks: {magnet_update:#?}");
+        let sent = board.subscriptions.publish(magnet_update);
+        tracing::trace!("Sent magnet update to {sent} websocket tasks");
+    }
+
+    /// How many updates are waiting to be sent to each session, on every
+    /// board.
+    pub fn queue_lengths(&self) -> Vec<usize> {
+        self.boards
+            .lock()
+            .unwrap()
+            .values()
+            .flat_map(|board| board.subscriptions.queue_lengths())
+            .collect()
+    }
+
+    /// Lets go of leases that have run out on every board, and tells the
+    /// sessions that can see the magnets.
+    pub fn expire_leases(&self) {
+        for board in self.boards.lock().unwrap().values() {
+            for release in board.leases.expire() {
+                board.subscriptions.publish_hold(release);
+            }
+        }
+    }
+
+    /// Tells sessions on every board that the change stream was interrupted.
+    pub fn broadcast_gap(&self) {
+        for board in self.boards.lock().unwrap().values() {
+            board.subscriptions.publish_gap();
+        }
+    }
+}
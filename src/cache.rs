@@ -0,0 +1,151 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use futures_util::TryStreamExt as _;
+
+use crate::{
+    geometry::{Shape, Window},
+    protocol::Magnet,
+    state::PgMagnetUpdate,
+    storage::Storage,
+};
+
+/// Width and height of the grid cells magnets are bucketed into.
+const CELL_SIZE: i32 = 1024;
+
+type Cell = (i32, i32);
+
+fn cell_at(x: i32, y: i32) -> Cell {
+    (x.div_euclid(CELL_SIZE), y.div_euclid(CELL_SIZE))
+}
+
+#[derive(Debug, Default)]
+struct BoardMagnets {
+    cells: HashMap<Cell, HashMap<i32, Magnet>>,
+    /// Which cell each magnet is currently in
+    cell_of: HashMap<i32, Cell>,
+}
+
+impl BoardMagnets {
+    fn upsert(&mut self, magnet: Magnet) {
+        if let Some(cell) = self.cell_of.get(&magnet.id) {
+            let cell_magnets = self.cells.get_mut(cell).unwrap();
+            // Notifications can arrive after a reload that already saw them
+            if cell_magnets[&magnet.id].z_index >= magnet.z_index {
+                return;
+            }
+
+            cell_magnets.remove(&magnet.id);
+            if cell_magnets.is_empty() {
+                self.cells.remove(cell);
+            }
+        }
+
+        let cell = cell_at(magnet.x, magnet.y);
+        self.cell_of.insert(magnet.id, cell);
+        self.cells
+            .entry(cell)
+            .or_default()
+            .insert(magnet.id, magnet);
+    }
+
+    fn get(&self, id: i32) -> Option<&Magnet> {
+        self.cells.get(self.cell_of.get(&id)?)?.get(&id)
+    }
+
+    fn in_window(&self, window: &Window) -> impl Iterator<Item = &Magnet> {
+        let (x1, y1) = cell_at(window.x1, window.y1);
+        let (x2, y2) = cell_at(window.x2, window.y2);
+        let cell_count = (i64::from(x2) - i64::from(x1) + 1) * (i64::from(y2) - i64::from(y1) + 1);
+
+        // Huge windows are cheaper to answer by looking at every cell we have
+        let cells: Box<dyn Iterator<Item = &HashMap<i32, Magnet>>> =
+            if cell_count > self.cells.len() as i64 {
+                Box::new(self.cells.values())
+            } else {
+                Box::new(
+                    (x1..=x2)
+                        .flat_map(move |x| (y1..=y2).map(move |y| (x, y)))
+                        .filter_map(|cell| self.cells.get(&cell)),
+                )
+            };
+
+        cells
+            .flat_map(HashMap::values)
+            .filter(|magnet| window.contains(magnet.x, magnet.y))
+    }
+}
+
+/// Every magnet on every board, kept in memory so that windows can be filled
+/// without asking storage. Storage stays the source of truth: the cache is
+/// only ever changed by the change stream.
+#[derive(Debug, Default)]
+pub struct MagnetCache {
+    boards: RwLock<HashMap<String, BoardMagnets>>,
+}
+
+impl MagnetCache {
+    pub async fn load(storage: &dyn Storage) -> Result<Arc<MagnetCache>, sqlx::Error> {
+        let cache = Arc::new(MagnetCache::default());
+        cache.reload(storage).await?;
+        Ok(cache)
+    }
+
+    /// Replaces the contents of the cache with what's in storage.
+    #[tracing::instrument(skip_all)]
+    pub async fn reload(&self, storage: &dyn Storage) -> Result<(), sqlx::Error> {
+        let mut boards: HashMap<String, BoardMagnets> = HashMap::new();
+
+        let mut magnets = storage.all_magnets();
+        let mut count = 0;
+        while let Some((board_id, magnet)) = magnets.try_next().await? {
+            boards.entry(board_id).or_default().upsert(magnet);
+            count += 1;
+        }
+
+        tracing::info!("Loaded {count} magnets into cache");
+        *self.boards.write().unwrap() = boards;
+        Ok(())
+    }
+
+    pub fn apply(&self, magnet_update: &PgMagnetUpdate) {
+        self.boards
+            .write()
+            .unwrap()
+            .entry(magnet_update.board_id.clone())
+            .or_default()
+            .upsert(Magnet {
+                id: magnet_update.id,
+                x: magnet_update.new_x,
+                y: magnet_update.new_y,
+                rotation: magnet_update.rotation,
+                z_index: magnet_update.z_index,
+                word: magnet_update.word.clone(),
+            });
+    }
+
+    /// Same as asking storage where a magnet on a board is.
+    pub fn magnet(&self, board_id: &str, id: i32) -> Option<Magnet> {
+        self.boards.read().unwrap().get(board_id)?.get(id).cloned()
+    }
+
+    /// Same as querying Postgres for the magnets on a board that are within
+    /// `shape`.
+    pub fn magnets_in(&self, board_id: &str, shape: &Shape) -> Vec<Magnet> {
+        let boards = self.boards.read().unwrap();
+        let Some(board) = boards.get(board_id) else {
+            return Vec::new();
+        };
+
+        match shape {
+            Shape::Window(window) => board.in_window(window).cloned().collect(),
+            Shape::Polygon(polygon) => board
+                .in_window(&polygon.bounding_window())
+                .filter(|magnet| polygon.contains(magnet.x, magnet.y))
+                .cloned()
+                .collect(),
+        }
+    }
+}
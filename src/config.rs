@@ -0,0 +1,363 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr as _,
+    time::Duration,
+};
+
+use anyhow::Result;
+use secrecy::SecretString;
+use serde::Deserialize;
+use tracing::Level;
+
+use crate::{
+    origins,
+    proxy::{self, ClientIpSource, TrustedProxies},
+    rate_limit::{Budget, RateLimits},
+    websocket::SessionTimeouts,
+};
+
+/// Environment variable naming a TOML file to read settings from. Keys in the
+/// file are the names of the environment variables, in lowercase, and
+/// environment variables take precedence over the file.
+const CONFIG_FILE_VAR: &str = "FRIDGE_CONFIG_FILE";
+
+const DEFAULT_IDLE_TIMEOUT_SECS: f64 = 300.0;
+const DEFAULT_HEARTBEAT_INTERVAL_SECS: f64 = 10.0;
+/// A week
+const DEFAULT_HISTORY_RETENTION_SECS: f64 = 604_800.0;
+
+#[derive(Deserialize, Debug, Default)]
+pub struct Config {
+    #[serde(rename = "fridge_log_level")]
+    pub log_level: Option<String>,
+    #[serde(rename = "fridge_trace_sample_rate")]
+    pub trace_sample_rate: Option<f32>,
+    #[serde(rename = "fridge_error_sample_rate")]
+    pub error_sample_rate: Option<f32>,
+    #[serde(rename = "fridge_broadcast_capacity")]
+    pub broadcast_capacity: Option<usize>,
+    #[serde(rename = "fridge_magnet_cache")]
+    pub magnet_cache: Option<bool>,
+    /// Origins allowed to connect, see `AllowedOrigins`. Any can if unset.
+    #[serde(rename = "fridge_allowed_origins", alias = "fridge_cors_origin")]
+    pub allowed_origins: Option<Vec<String>>,
+    #[serde(rename = "fridge_listen_addr")]
+    pub listen_addr: Option<SocketAddr>,
+    /// Where to serve `/metrics`, which isn't served at all if unset. Meant
+    /// for an internal address that Prometheus can reach but clients can't.
+    #[serde(rename = "fridge_metrics_addr")]
+    pub metrics_addr: Option<SocketAddr>,
+    /// Where to find the client's IP when connected through a trusted proxy
+    #[serde(rename = "fridge_client_ip_source")]
+    pub client_ip_source: Option<ClientIpSource>,
+    /// Addresses or CIDRs of the proxies in front, the only connections
+    /// whose word is taken for the client's IP
+    #[serde(rename = "fridge_trusted_proxies")]
+    pub trusted_proxies: Option<Vec<String>>,
+    /// PEM certificate chain to serve `wss://` with, reloaded on SIGHUP
+    #[serde(rename = "fridge_tls_cert")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key for `tls_cert`
+    #[serde(rename = "fridge_tls_key")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Seconds a session can go without hearing from its client
+    #[serde(rename = "fridge_idle_timeout_secs")]
+    pub idle_timeout_secs: Option<f64>,
+    /// Seconds between pings to a quiet client
+    #[serde(rename = "fridge_heartbeat_interval_secs")]
+    pub heartbeat_interval_secs: Option<f64>,
+    /// Seconds back in time that history can be asked for, older moves are
+    /// deleted
+    #[serde(rename = "fridge_history_retention_secs")]
+    pub history_retention_secs: Option<f64>,
+
+    #[serde(rename = "fridge_window_updates_per_second")]
+    pub window_updates_per_second: Option<f64>,
+    #[serde(rename = "fridge_window_update_burst")]
+    pub window_update_burst: Option<f64>,
+    #[serde(rename = "fridge_moves_per_second")]
+    pub moves_per_second: Option<f64>,
+    #[serde(rename = "fridge_move_burst")]
+    pub move_burst: Option<f64>,
+    #[serde(rename = "fridge_sessions_per_ip")]
+    pub sessions_per_ip: Option<f64>,
+    #[serde(rename = "fridge_storage")]
+    pub storage: Option<StorageKind>,
+    /// Magnets to start in-memory storage with, in the format written by
+    /// `generate_table`
+    #[serde(rename = "fridge_seed_file")]
+    pub seed_file: Option<PathBuf>,
+
+    #[serde(rename = "fridge_db_max_connections")]
+    pub db_max_connections: Option<u32>,
+    /// Seconds to wait for a connection from the pool before giving up
+    #[serde(rename = "fridge_db_acquire_timeout_secs")]
+    pub db_acquire_timeout_secs: Option<f64>,
+
+    pub sentry_dsn: Option<SecretString>,
+    /// Required unless using in-memory storage
+    pub database_url: Option<SecretString>,
+}
+
+#[derive(Clone, Copy, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageKind {
+    Postgres,
+    Memory,
+}
+
+impl Config {
+    /// Reads the config from the environment and the config file, if there
+    /// is one, and checks it. Every problem found is reported at once.
+    pub fn load() -> Result<Config> {
+        let mut vars: HashMap<String, String> = std::env::vars()
+            .map(|(key, value)| (key.to_lowercase(), value))
+            .collect();
+        if let Some(path) = vars.get(&CONFIG_FILE_VAR.to_lowercase()) {
+            for (key, value) in read_config_file(Path::new(path))? {
+                vars.entry(key).or_insert(value);
+            }
+        }
+
+        // Find every value that's the wrong type, not just the first, and
+        // leave them out so the rest can still be checked
+        let mut errors = Vec::new();
+        vars.retain(|key, value| {
+            match envy::from_iter::<_, Config>([(key.clone(), value.clone())]) {
+                Ok(_) => true,
+                Err(e) => {
+                    let e = e.to_string();
+                    if e.contains(key.as_str()) {
+                        errors.push(e);
+                    } else {
+                        errors.push(format!("{}: {e}", key.to_uppercase()));
+                    }
+                    false
+                }
+            }
+        });
+        errors.sort();
+
+        let config = envy::from_iter::<_, Config>(vars)?;
+        errors.extend(config.validate());
+        if !errors.is_empty() {
+            return Err(invalid(errors));
+        }
+        Ok(config)
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if let Some(log_level) = &self.log_level
+            && Level::from_str(log_level).is_err()
+        {
+            errors.push(format!("Invalid value for FRIDGE_LOG_LEVEL: {log_level}"));
+        }
+        for (name, rate) in [
+            ("FRIDGE_TRACE_SAMPLE_RATE", self.trace_sample_rate),
+            ("FRIDGE_ERROR_SAMPLE_RATE", self.error_sample_rate),
+        ] {
+            if let Some(rate) = rate
+                && !(0.0..=1.0).contains(&rate)
+            {
+                errors.push(format!("{name} must be between 0 and 1"));
+            }
+        }
+        if self.broadcast_capacity == Some(0) {
+            errors.push("FRIDGE_BROADCAST_CAPACITY must be at least 1".to_string());
+        }
+        if self.db_max_connections == Some(0) {
+            errors.push("FRIDGE_DB_MAX_CONNECTIONS must be at least 1".to_string());
+        }
+
+        for (name, secs) in [
+            ("FRIDGE_IDLE_TIMEOUT_SECS", self.idle_timeout_secs),
+            (
+                "FRIDGE_HEARTBEAT_INTERVAL_SECS",
+                self.heartbeat_interval_secs,
+            ),
+            ("FRIDGE_HISTORY_RETENTION_SECS", self.history_retention_secs),
+            (
+                "FRIDGE_DB_ACQUIRE_TIMEOUT_SECS",
+                self.db_acquire_timeout_secs,
+            ),
+        ] {
+            if let Some(secs) = secs
+                && !(secs > 0.0 && Duration::try_from_secs_f64(secs).is_ok())
+            {
+                errors.push(format!("{name} must be a positive number of seconds"));
+            }
+        }
+        if self
+            .heartbeat_interval_secs
+            .unwrap_or(DEFAULT_HEARTBEAT_INTERVAL_SECS)
+            >= self.idle_timeout_secs.unwrap_or(DEFAULT_IDLE_TIMEOUT_SECS)
+        {
+            errors.push(
+                "FRIDGE_HEARTBEAT_INTERVAL_SECS must be shorter than FRIDGE_IDLE_TIMEOUT_SECS"
+                    .to_string(),
+            );
+        }
+
+        let rate_limits = self.rate_limits();
+        for (name, budget) in [
+            ("window update", rate_limits.window_updates),
+            ("move", rate_limits.moves),
+        ] {
+            if !(budget.per_second > 0.0 && budget.burst >= 1.0) {
+                errors.push(format!("Invalid {name} rate limit: {budget:?}"));
+            }
+        }
+        if rate_limits.sessions_per_peer.is_nan() || rate_limits.sessions_per_peer < 1.0 {
+            errors.push("FRIDGE_SESSIONS_PER_IP must be at least 1".to_string());
+        }
+
+        for pattern in self.allowed_origins.iter().flatten() {
+            if let Err(e) = origins::validate_pattern(pattern) {
+                errors.push(e);
+            }
+        }
+
+        for network in self.trusted_proxies.iter().flatten() {
+            if let Err(e) = proxy::parse_network(network) {
+                errors.push(e);
+            }
+        }
+        if self.client_ip_source.unwrap_or_default() != ClientIpSource::Peer
+            && self.trusted_proxies.as_ref().is_none_or(Vec::is_empty)
+        {
+            errors.push(
+                "FRIDGE_TRUSTED_PROXIES must be set to take the client's IP from a proxy"
+                    .to_string(),
+            );
+        }
+
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            errors.push("FRIDGE_TLS_CERT and FRIDGE_TLS_KEY must be set together".to_string());
+        }
+
+        if matches!(self.storage(), StorageKind::Postgres) && self.database_url.is_none() {
+            errors.push("DATABASE_URL must be set when using Postgres storage".to_string());
+        }
+
+        errors
+    }
+
+    pub fn storage(&self) -> StorageKind {
+        self.storage.unwrap_or(StorageKind::Postgres)
+    }
+
+    pub fn listen_addr(&self) -> SocketAddr {
+        self.listen_addr
+            .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 8080)))
+    }
+
+    pub fn broadcast_capacity(&self) -> usize {
+        self.broadcast_capacity.unwrap_or(100)
+    }
+
+    pub fn db_max_connections(&self) -> u32 {
+        self.db_max_connections.unwrap_or(5)
+    }
+
+    pub fn db_acquire_timeout(&self) -> Duration {
+        Duration::from_secs_f64(self.db_acquire_timeout_secs.unwrap_or(30.0))
+    }
+
+    pub fn session_timeouts(&self) -> SessionTimeouts {
+        SessionTimeouts {
+            idle: Duration::from_secs_f64(
+                self.idle_timeout_secs.unwrap_or(DEFAULT_IDLE_TIMEOUT_SECS),
+            ),
+            heartbeat: Duration::from_secs_f64(
+                self.heartbeat_interval_secs
+                    .unwrap_or(DEFAULT_HEARTBEAT_INTERVAL_SECS),
+            ),
+        }
+    }
+
+    pub fn history_retention(&self) -> Duration {
+        Duration::from_secs_f64(
+            self.history_retention_secs
+                .unwrap_or(DEFAULT_HISTORY_RETENTION_SECS),
+        )
+    }
+
+    pub fn trusted_proxies(&self) -> TrustedProxies {
+        TrustedProxies::new(
+            self.client_ip_source.unwrap_or_default(),
+            self.trusted_proxies
+                .iter()
+                .flatten()
+                .filter_map(|network| proxy::parse_network(network).ok())
+                .collect(),
+        )
+    }
+
+    pub fn rate_limits(&self) -> RateLimits {
+        let window_updates_per_second = self.window_updates_per_second.unwrap_or(5.0);
+        let moves_per_second = self.moves_per_second.unwrap_or(5.0);
+        RateLimits {
+            window_updates: Budget {
+                per_second: window_updates_per_second,
+                burst: self
+                    .window_update_burst
+                    .unwrap_or(window_updates_per_second),
+            },
+            moves: Budget {
+                per_second: moves_per_second,
+                burst: self.move_burst.unwrap_or(moves_per_second),
+            },
+            sessions_per_peer: self.sessions_per_ip.unwrap_or(4.0),
+        }
+    }
+}
+
+/// Reads settings from a TOML file as if they were environment variables, so
+/// that both are parsed the same way. Lists become comma separated values.
+fn read_config_file(path: &Path) -> Result<Vec<(String, String)>> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Unable to read {}: {e}", path.display()))?;
+    let table: toml::Table = toml::from_str(&contents)
+        .map_err(|e| anyhow::anyhow!("Unable to parse {}: {e}", path.display()))?;
+
+    let mut vars = Vec::new();
+    let mut errors = Vec::new();
+    for (key, value) in table {
+        let value = match value {
+            toml::Value::Array(values) => values
+                .into_iter()
+                .map(|value| to_var(&key, value))
+                .collect::<Result<Vec<_>, _>>()
+                .map(|values| values.join(",")),
+            value => to_var(&key, value),
+        };
+        match value {
+            Ok(value) => vars.push((key.to_lowercase(), value)),
+            Err(e) => errors.push(e),
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(invalid(errors));
+    }
+    Ok(vars)
+}
+
+fn to_var(key: &str, value: toml::Value) -> Result<String, String> {
+    match value {
+        toml::Value::String(value) => Ok(value),
+        toml::Value::Integer(_) | toml::Value::Float(_) | toml::Value::Boolean(_) => {
+            Ok(value.to_string())
+        }
+        _ => Err(format!("Unsupported value for {key}: {value}")),
+    }
+}
+
+fn invalid(errors: Vec<String>) -> anyhow::Error {
+    anyhow::anyhow!("Invalid configuration:\n  {}", errors.join("\n  "))
+}
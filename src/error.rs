@@ -1,6 +1,8 @@
 use thiserror::Error;
 use tokio_websockets::{CloseCode, Message};
 
+use crate::protocol::ErrorCode;
+
 #[derive(Debug, Error)]
 pub enum FridgeError {
     #[error("Server shutting down")]
@@ -9,6 +11,9 @@ pub enum FridgeError {
     #[error("Request exceeds rate limits")]
     RateLimited,
 
+    #[error("Client keeps exceeding rate limits")]
+    Abusive,
+
     #[error("WebSocket connection closed by client")]
     ClientClose(Option<(CloseCode, String)>),
 
@@ -24,6 +29,18 @@ pub enum FridgeError {
     #[error("Out of bounds update")]
     OutOfBounds(String),
 
+    #[error("Magnet has been moved since the client last saw it")]
+    Conflict,
+
+    #[error("Magnet is being held by someone else")]
+    Held,
+
+    #[error("Session is holding too many magnets")]
+    TooManyHeld,
+
+    #[error("Magnet has to be grabbed first")]
+    NotHeld,
+
     #[error(transparent)]
     Tungstenite(#[from] tokio_websockets::Error),
 
@@ -35,6 +52,42 @@ pub enum FridgeError {
 }
 
 impl FridgeError {
+    /// What to tell the client if this only means its request was bad, and
+    /// the connection can carry on.
+    pub fn to_error_code(&self) -> Option<ErrorCode> {
+        match self {
+            FridgeError::OutOfBounds(_) => Some(ErrorCode::OutOfBounds),
+            FridgeError::Sqlx(sqlx::Error::RowNotFound) => Some(ErrorCode::NotFound),
+            FridgeError::Conflict => Some(ErrorCode::Conflict),
+            FridgeError::Held => Some(ErrorCode::Held),
+            FridgeError::TooManyHeld => Some(ErrorCode::TooManyHeld),
+            FridgeError::NotHeld => Some(ErrorCode::NotHeld),
+            _ => None,
+        }
+    }
+
+    /// Why a session ended, for metrics.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            FridgeError::Shutdown => "shutdown",
+            FridgeError::RateLimited => "rate_limited",
+            FridgeError::Abusive => "abusive",
+            FridgeError::ClientClose(_) => "client_close",
+            FridgeError::IdleTimeout => "idle_timeout",
+            FridgeError::UnsupportedMessage(_) => "unsupported_message",
+            FridgeError::InvalidMessage(_) => "invalid_message",
+            FridgeError::OutOfBounds(_) => "out_of_bounds",
+            FridgeError::Sqlx(sqlx::Error::RowNotFound) => "not_found",
+            FridgeError::Conflict => "conflict",
+            FridgeError::Held => "held",
+            FridgeError::TooManyHeld => "too_many_held",
+            FridgeError::NotHeld => "not_held",
+            FridgeError::Tungstenite(_) => "websocket",
+            FridgeError::Sqlx(_) => "database",
+            FridgeError::Other(_) => "internal",
+        }
+    }
+
     pub fn to_close_message(&self) -> Option<Message> {
         match self {
             FridgeError::Shutdown => Some(Message::close(Some(CloseCode::SERVICE_RESTART), "")),
@@ -54,9 +107,13 @@ impl FridgeError {
                 // don't expect this one to complete
                 Some(Message::close(Some(CloseCode::INTERNAL_SERVER_ERROR), ""))
             }
-            FridgeError::Sqlx(sqlx::Error::RowNotFound) | FridgeError::OutOfBounds(_) => {
-                Some(Message::close(Some(CloseCode::POLICY_VIOLATION), ""))
-            }
+            FridgeError::Sqlx(sqlx::Error::RowNotFound)
+            | FridgeError::OutOfBounds(_)
+            | FridgeError::Conflict
+            | FridgeError::Held
+            | FridgeError::TooManyHeld
+            | FridgeError::NotHeld
+            | FridgeError::Abusive => Some(Message::close(Some(CloseCode::POLICY_VIOLATION), "")),
             FridgeError::Other(_) | FridgeError::Sqlx(_) => {
                 Some(Message::close(Some(CloseCode::INTERNAL_SERVER_ERROR), ""))
             }
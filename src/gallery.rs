@@ -0,0 +1,80 @@
+use http::{Request, StatusCode};
+use rand::{Rng as _, distr::Alphanumeric};
+use serde::Serialize;
+use sqlx::types::chrono::{DateTime, Utc};
+use tokio::io::AsyncWrite;
+
+use crate::{geometry::Window, handshake, poems, protocol::Magnet, state::AppState};
+
+const SLUG_LENGTH: usize = 8;
+/// Largest area that can be saved as a poem, in each direction.
+pub const MAX_POEM_SIZE: i32 = 4096;
+
+/// A window of magnets as they were when someone saved it.
+#[derive(Clone, Debug)]
+pub struct SavedPoem {
+    pub slug: String,
+    pub board_id: String,
+    pub window: Window,
+    pub magnets: Vec<Magnet>,
+    pub created_at: DateTime<Utc>,
+}
+
+pub fn new_slug() -> String {
+    rand::rng()
+        .sample_iter(Alphanumeric)
+        .take(SLUG_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+#[derive(Serialize)]
+struct SavedPoemResponse<'a> {
+    slug: &'a str,
+    board_id: &'a str,
+    window: &'a Window,
+    magnets: &'a [Magnet],
+    /// In milliseconds since the Unix epoch
+    created_at: i64,
+    /// What the magnets say, as best as can be made out
+    text: String,
+}
+
+/// Answers `GET /poems/{slug}` with the saved poem as JSON.
+#[tracing::instrument(skip(stream, request, state))]
+pub async fn serve_saved_poem<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    slug: &str,
+    request: &Request<()>,
+    state: &AppState,
+) -> std::io::Result<()> {
+    let poem = match state.storage.saved_poem(slug).await {
+        Ok(Some(poem)) => poem,
+        Ok(None) => return handshake::reject(stream, StatusCode::NOT_FOUND).await,
+        Err(e) => {
+            tracing::error!("Unable to look up poem {slug}: {e}");
+            return handshake::reject(stream, StatusCode::INTERNAL_SERVER_ERROR).await;
+        }
+    };
+
+    let text = poems::detect(&poem.magnets)
+        .iter()
+        .map(poems::Poem::text)
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    let body = serde_json::to_vec(&SavedPoemResponse {
+        slug: &poem.slug,
+        board_id: &poem.board_id,
+        window: &poem.window,
+        magnets: &poem.magnets,
+        created_at: poem.created_at.timestamp_millis(),
+        text,
+    })?;
+
+    let mut headers = vec![("Content-Type", "application/json")];
+    if let Some(cors_origin) = state.allowed_origins.cors_origin(request.headers()) {
+        headers.push(("Access-Control-Allow-Origin", cors_origin));
+        headers.push(("Vary", "Origin"));
+    }
+    handshake::respond(stream, StatusCode::OK, &headers, &body).await
+}
@@ -16,6 +16,51 @@ pub struct Polygon {
     pub p6: Point,
 }
 
+impl Polygon {
+    fn points(&self) -> [Point; 6] {
+        [self.p1, self.p2, self.p3, self.p4, self.p5, self.p6]
+    }
+
+    /// Smallest window containing the whole polygon.
+    pub fn bounding_window(&self) -> Window {
+        let points = self.points();
+        Window {
+            x1: points.iter().map(|p| p.x).min().unwrap(),
+            y1: points.iter().map(|p| p.y).min().unwrap(),
+            x2: points.iter().map(|p| p.x).max().unwrap(),
+            y2: points.iter().map(|p| p.y).max().unwrap(),
+        }
+    }
+
+    /// Whether the point is inside the polygon or on its edge, to match
+    /// Postgres's `<@`.
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        let points = self.points();
+        let mut inside = false;
+
+        for (a, b) in points.iter().zip(points.iter().cycle().skip(1)) {
+            let cross = i64::from(b.x - a.x) * i64::from(y - a.y)
+                - i64::from(b.y - a.y) * i64::from(x - a.x);
+            if cross == 0
+                && (a.x.min(b.x)..=a.x.max(b.x)).contains(&x)
+                && (a.y.min(b.y)..=a.y.max(b.y)).contains(&y)
+            {
+                return true;
+            }
+
+            if (a.y > y) != (b.y > y) {
+                let edge_x = f64::from(a.x)
+                    + f64::from(y - a.y) * f64::from(b.x - a.x) / f64::from(b.y - a.y);
+                if f64::from(x) < edge_x {
+                    inside = !inside;
+                }
+            }
+        }
+
+        inside
+    }
+}
+
 #[derive(Debug)]
 pub enum Shape {
     Window(Window),
@@ -245,3 +290,73 @@ impl Window {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(x1: i32, y1: i32, x2: i32, y2: i32) -> Window {
+        Window { x1, y1, x2, y2 }
+    }
+
+    /// What's in `new` that `old` didn't already show, which is what
+    /// `difference` is for. Points on the edge of `old` are sent again, as
+    /// Postgres's `<@` counts edges as inside.
+    fn newly_visible(old: &Window, new: &Window, x: i32, y: i32) -> bool {
+        let inside_old = old.x1 < x && x < old.x2 && old.y1 < y && y < old.y2;
+        new.contains(x, y) && !inside_old
+    }
+
+    #[test]
+    fn polygon_contains_what_a_diagonal_move_uncovers() {
+        let old = window(0, 0, 100, 100);
+        for (dx, dy) in [(50, 50), (50, -50), (-50, 50), (-50, -50)] {
+            let new = window(dx, dy, 100 + dx, 100 + dy);
+            let Some(Shape::Polygon(polygon)) = old.difference(&new) else {
+                panic!("Moving by ({dx}, {dy}) should uncover a polygon");
+            };
+
+            for x in (-60..=160).step_by(5) {
+                for y in (-60..=160).step_by(5) {
+                    assert_eq!(
+                        polygon.contains(x, y),
+                        newly_visible(&old, &new, x, y),
+                        "({x}, {y}) after moving by ({dx}, {dy})"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn polygon_contains_its_edges_and_corners() {
+        let Some(Shape::Polygon(polygon)) =
+            window(0, 0, 100, 100).difference(&window(50, 50, 150, 150))
+        else {
+            panic!("Expected a polygon");
+        };
+
+        for point in polygon.points() {
+            assert!(polygon.contains(point.x, point.y), "{point:?}");
+        }
+        assert!(polygon.contains(75, 100));
+        assert!(polygon.contains(100, 75));
+        assert!(polygon.contains(150, 120));
+        assert!(!polygon.contains(75, 75));
+        assert!(!polygon.contains(151, 120));
+    }
+
+    #[test]
+    fn polygon_bounding_window_is_the_new_window() {
+        let new = window(-50, 50, 50, 150);
+        let Some(Shape::Polygon(polygon)) = window(0, 0, 100, 100).difference(&new) else {
+            panic!("Expected a polygon");
+        };
+
+        let bounds = polygon.bounding_window();
+        assert_eq!(
+            (bounds.x1, bounds.y1, bounds.x2, bounds.y2),
+            (new.x1, new.y1, new.x2, new.y2)
+        );
+    }
+}
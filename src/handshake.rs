@@ -0,0 +1,170 @@
+use std::{str::FromStr as _, time::Duration};
+
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use http::{HeaderMap, HeaderName, HeaderValue, Request, StatusCode};
+use sha1::{Digest as _, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+use tokio_websockets::upgrade;
+
+// Defined by RFC 6455, appended to the client key before hashing
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_REQUEST_SIZE: usize = 8192;
+/// How long a client has to send its request once connected, the same as it
+/// gets for the TLS handshake.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Reads the HTTP/1.1 request that opens a connection. We do the upgrade
+/// handshake ourselves rather than through `ServerBuilder::accept` so that we
+/// can choose which headers go into the response.
+#[tracing::instrument(skip(stream))]
+pub async fn read_request<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<Request<()>, tokio_websockets::Error> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0; 1024];
+
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut parsed = httparse::Request::new(&mut headers);
+        match parsed.parse(&buf).map_err(upgrade::Error::from)? {
+            httparse::Status::Complete(_) => return build_request(&parsed),
+            httparse::Status::Partial if buf.len() >= MAX_REQUEST_SIZE => {
+                return Err(upgrade::Error::Parsing(httparse::Error::TooManyHeaders).into());
+            }
+            httparse::Status::Partial => {}
+        }
+    }
+}
+
+fn build_request(parsed: &httparse::Request) -> Result<Request<()>, tokio_websockets::Error> {
+    let mut builder = Request::builder();
+    if let Some(method) = parsed.method {
+        builder = builder.method(method);
+    }
+    if let Some(path) = parsed.path {
+        builder = builder.uri(path);
+    }
+
+    let mut headers = HeaderMap::with_capacity(parsed.headers.len());
+    for h in parsed.headers.iter() {
+        let name = HeaderName::from_str(h.name)
+            .map_err(|_| upgrade::Error::Parsing(httparse::Error::HeaderName))?;
+        let value = HeaderValue::from_bytes(h.value)
+            .map_err(|_| upgrade::Error::Parsing(httparse::Error::HeaderValue))?;
+        headers.append(name, value);
+    }
+
+    let mut request = builder
+        .body(())
+        .map_err(|_| upgrade::Error::Parsing(httparse::Error::Token))?;
+    *request.headers_mut() = headers;
+    Ok(request)
+}
+
+fn find_header<'a>(
+    request: &'a Request<()>,
+    name: &'static str,
+) -> Result<&'a str, upgrade::Error> {
+    request
+        .headers()
+        .get(name)
+        .and_then(|hv| hv.to_str().ok())
+        .ok_or(upgrade::Error::MissingHeader(name))
+}
+
+/// Checks that the request is a valid WebSocket upgrade and returns the value
+/// for the `Sec-WebSocket-Accept` response header.
+fn validate_upgrade(request: &Request<()>) -> Result<String, upgrade::Error> {
+    if !find_header(request, "Upgrade")?.eq_ignore_ascii_case("websocket") {
+        return Err(upgrade::Error::UpgradeNotWebSocket);
+    }
+
+    if !find_header(request, "Connection")?
+        .split(',')
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+    {
+        return Err(upgrade::Error::ConnectionNotUpgrade);
+    }
+
+    if find_header(request, "Sec-WebSocket-Version")? != "13" {
+        return Err(upgrade::Error::UnsupportedWebSocketVersion);
+    }
+
+    let key = find_header(request, "Sec-WebSocket-Key")?;
+    let digest = Sha1::new()
+        .chain_update(key.as_bytes())
+        .chain_update(WEBSOCKET_GUID.as_bytes())
+        .finalize();
+    Ok(STANDARD.encode(digest))
+}
+
+/// Whether the request is asking to be upgraded to a WebSocket.
+pub fn is_upgrade(request: &Request<()>) -> bool {
+    validate_upgrade(request).is_ok()
+}
+
+/// Completes the upgrade handshake with a `101 Switching Protocols`,
+/// advertising `subprotocol` if one was negotiated. Invalid upgrade requests
+/// are answered with a `400 Bad Request`.
+#[tracing::instrument(skip(stream, request))]
+pub async fn accept<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    request: &Request<()>,
+    subprotocol: Option<&str>,
+) -> Result<(), tokio_websockets::Error> {
+    let ws_accept = match validate_upgrade(request) {
+        Ok(ws_accept) => ws_accept,
+        Err(e) => {
+            reject(stream, StatusCode::BAD_REQUEST).await?;
+            return Err(e.into());
+        }
+    };
+
+    let mut response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: \
+         Upgrade\r\nSec-WebSocket-Accept: {ws_accept}\r\n"
+    );
+    if let Some(subprotocol) = subprotocol {
+        response.push_str(&format!("Sec-WebSocket-Protocol: {subprotocol}\r\n"));
+    }
+    response.push_str("\r\n");
+
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+/// Responds with an empty-bodied HTTP error and no upgrade.
+pub async fn reject<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    status: StatusCode,
+) -> std::io::Result<()> {
+    respond(stream, status, &[], &[]).await
+}
+
+/// Responds to a plain HTTP request and closes the connection.
+pub async fn respond<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    status: StatusCode,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> std::io::Result<()> {
+    let mut response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or_default(),
+        body.len()
+    );
+    for (name, value) in headers {
+        response.push_str(&format!("{name}: {value}\r\n"));
+    }
+    response.push_str("\r\n");
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.write_all(body).await
+}
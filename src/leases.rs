@@ -0,0 +1,254 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use uuid::Uuid;
+
+use crate::error::FridgeError;
+
+/// How long a grab lasts unless the holder grabs the magnet again.
+pub const LEASE_DURATION: Duration = Duration::from_secs(10);
+/// How often expired leases are let go of, so viewers hear about it about as
+/// soon as their timer runs out.
+pub const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+/// How many magnets a session can hold at once, so that one client can't
+/// freeze a whole poem.
+const MAX_LEASES_PER_SESSION: usize = 5;
+
+/// A magnet being grabbed or let go of, for the sessions that can see it.
+#[derive(Clone, Debug)]
+pub struct HoldUpdate {
+    pub id: i32,
+    pub x: i32,
+    pub y: i32,
+    pub holder: Uuid,
+    /// How long the hold lasts unless renewed, or `None` once it's released
+    pub expires_in: Option<Duration>,
+}
+
+/// Where a magnet is on its way to while someone is dragging it. Never stored,
+/// only the move at the end of the drag is.
+#[derive(Clone, Debug)]
+pub struct DragUpdate {
+    pub id: i32,
+    pub old_x: i32,
+    pub old_y: i32,
+    pub x: i32,
+    pub y: i32,
+    pub rotation: i32,
+    pub dragger: Uuid,
+}
+
+#[derive(Debug)]
+struct Lease {
+    holder: Uuid,
+    x: i32,
+    y: i32,
+    expires_at: Instant,
+}
+
+impl Lease {
+    fn is_held_by_other(&self, session_id: &Uuid, now: Instant) -> bool {
+        self.holder != *session_id && self.expires_at > now
+    }
+}
+
+/// Which sessions are holding which magnets on a board. Only this server
+/// knows about them, unlike moves.
+#[derive(Debug, Default)]
+pub struct Leases {
+    leases: Mutex<HashMap<i32, Lease>>,
+}
+
+impl Leases {
+    /// Takes or renews a lease on a magnet at `(x, y)`. Fails if another
+    /// session is holding it, or if this session is already holding as many
+    /// magnets as it can.
+    pub fn grab(
+        &self,
+        id: i32,
+        x: i32,
+        y: i32,
+        session_id: &Uuid,
+    ) -> Result<HoldUpdate, FridgeError> {
+        let now = Instant::now();
+        let mut leases = self.leases.lock().unwrap();
+
+        // Expired leases are left to `expire`, so that their release is heard
+        match leases.get(&id) {
+            Some(lease) if lease.is_held_by_other(session_id, now) => {
+                return Err(FridgeError::Held);
+            }
+            // Renewing doesn't take another lease
+            Some(lease) if lease.expires_at > now => {}
+            _ => {
+                let held = leases
+                    .values()
+                    .filter(|lease| lease.holder == *session_id && lease.expires_at > now)
+                    .count();
+                if held >= MAX_LEASES_PER_SESSION {
+                    return Err(FridgeError::TooManyHeld);
+                }
+            }
+        }
+
+        leases.insert(
+            id,
+            Lease {
+                holder: *session_id,
+                x,
+                y,
+                expires_at: now + LEASE_DURATION,
+            },
+        );
+        Ok(HoldUpdate {
+            id,
+            x,
+            y,
+            holder: *session_id,
+            expires_in: Some(LEASE_DURATION),
+        })
+    }
+
+    /// Whether a session can move a magnet to `(x, y)`, which it can unless
+    /// someone else is holding it. Moves don't renew the holder's lease, as
+    /// nobody else would hear about it.
+    pub fn can_move(&self, id: i32, x: i32, y: i32, session_id: &Uuid) -> bool {
+        let now = Instant::now();
+        let mut leases = self.leases.lock().unwrap();
+        match leases.get_mut(&id) {
+            Some(lease) if lease.is_held_by_other(session_id, now) => false,
+            Some(lease) => {
+                // So that letting go is heard where the magnet ended up
+                lease.x = x;
+                lease.y = y;
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// Moves a magnet the session is holding along to `(x, y)`, returning
+    /// where it was dragged from. Only the holder can drag a magnet, and like
+    /// moves, drags don't renew the lease.
+    pub fn drag(
+        &self,
+        id: i32,
+        x: i32,
+        y: i32,
+        session_id: &Uuid,
+    ) -> Result<(i32, i32), FridgeError> {
+        let now = Instant::now();
+        let mut leases = self.leases.lock().unwrap();
+        match leases.get_mut(&id) {
+            Some(lease) if lease.is_held_by_other(session_id, now) => Err(FridgeError::Held),
+            Some(lease) if lease.holder == *session_id && lease.expires_at > now => {
+                let from = (lease.x, lease.y);
+                lease.x = x;
+                lease.y = y;
+                Ok(from)
+            }
+            _ => Err(FridgeError::NotHeld),
+        }
+    }
+
+    /// Lets go of a magnet if the session is holding it.
+    pub fn release(&self, id: i32, session_id: &Uuid) -> Option<HoldUpdate> {
+        let mut leases = self.leases.lock().unwrap();
+        if leases.get(&id)?.holder != *session_id {
+            return None;
+        }
+
+        let lease = leases.remove(&id)?;
+        Some(HoldUpdate {
+            id,
+            x: lease.x,
+            y: lease.y,
+            holder: *session_id,
+            expires_in: None,
+        })
+    }
+
+    /// Lets go of everything a session is holding, for when it goes away.
+    pub fn release_all(&self, session_id: &Uuid) -> Vec<HoldUpdate> {
+        self.release_where(|lease| lease.holder == *session_id)
+    }
+
+    /// Lets go of every lease that has run out.
+    pub fn expire(&self) -> Vec<HoldUpdate> {
+        let now = Instant::now();
+        self.release_where(|lease| lease.expires_at <= now)
+    }
+
+    fn release_where(&self, mut predicate: impl FnMut(&Lease) -> bool) -> Vec<HoldUpdate> {
+        let mut releases = Vec::new();
+        self.leases.lock().unwrap().retain(|&id, lease| {
+            if !predicate(lease) {
+                return true;
+            }
+
+            releases.push(HoldUpdate {
+                id,
+                x: lease.x,
+                y: lease.y,
+                holder: lease.holder,
+                expires_in: None,
+            });
+            false
+        });
+        releases
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Leases that ran out a moment ago, held by `holder`.
+    fn expired(leases: &Leases, ids: &[i32], holder: Uuid) {
+        let expires_at = Instant::now() - Duration::from_millis(1);
+        let mut leases = leases.leases.lock().unwrap();
+        for &id in ids {
+            leases.insert(
+                id,
+                Lease {
+                    holder,
+                    x: id,
+                    y: id,
+                    expires_at,
+                },
+            );
+        }
+    }
+
+    #[test]
+    fn expired_leases_are_released_once() {
+        let leases = Leases::default();
+        let holder = Uuid::now_v7();
+        expired(&leases, &[1], holder);
+        leases.grab(2, 0, 0, &holder).unwrap();
+
+        let releases = leases.expire();
+        assert_eq!(releases.len(), 1);
+        assert_eq!(
+            (releases[0].id, releases[0].x, releases[0].holder),
+            (1, 1, holder)
+        );
+        assert_eq!(releases[0].expires_in, None);
+
+        assert!(leases.expire().is_empty());
+    }
+
+    #[test]
+    fn expired_leases_dont_count_against_anyone() {
+        let leases = Leases::default();
+        let holder = Uuid::now_v7();
+        let other = Uuid::now_v7();
+        expired(&leases, &[1, 2, 3, 4, 5], holder);
+
+        leases.grab(1, 0, 0, &other).unwrap();
+        leases.grab(6, 0, 0, &holder).unwrap();
+    }
+}
@@ -1,65 +1,91 @@
+mod board;
+mod cache;
+mod config;
 mod error;
+mod gallery;
 mod geometry;
+mod handshake;
+mod leases;
+mod metrics;
+mod origins;
+mod poems;
+mod presence;
+mod protocol;
+mod proxy;
+mod rate_limit;
+mod resume;
+mod routes;
 mod state;
+mod storage;
+mod tiles;
+mod tls;
 mod websocket;
 
-use std::str::FromStr as _;
+use std::{
+    str::FromStr as _,
+    sync::{Arc, atomic::Ordering},
+    time::Duration,
+};
 
 use anyhow::Result;
-use error::FridgeError;
+use http::StatusCode;
 use mimalloc::MiMalloc;
-use secrecy::{ExposeSecret as _, SecretString};
-use serde::Deserialize;
-use sqlx::postgres::PgListener;
+use secrecy::ExposeSecret as _;
+use sqlx::types::chrono::{DateTime, Utc};
 use tokio::{
     net::{TcpListener, TcpStream},
     select, signal,
-    sync::broadcast,
+    time::{MissedTickBehavior, interval, timeout},
 };
 use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use tracing::{Level, level_filters::LevelFilter};
 use tracing_subscriber::{layer::SubscriberExt as _, util::SubscriberInitExt as _};
 use uuid::Uuid;
 
-use crate::state::{AppState, PgMagnetUpdate};
+use crate::{
+    board::{Boards, DEFAULT_BOARD},
+    cache::MagnetCache,
+    config::{Config, StorageKind},
+    geometry::{Shape, Window},
+    metrics::Metrics,
+    origins::AllowedOrigins,
+    protocol::Protocol,
+    rate_limit::RateLimiter,
+    resume::ResumeRequest,
+    routes::Route,
+    state::{AppState, ChangeEvent},
+    storage::{ChangeFeed, MemoryStorage, PgStorage, Storage},
+    tls::{Stream, Tls},
+};
+
+/// How often moves older than the history retention are deleted
+const HISTORY_PRUNE_INTERVAL: Duration = Duration::from_secs(600);
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
-#[derive(Deserialize, Debug)]
-pub struct Config {
-    #[serde(rename = "fridge_log_level")]
-    pub log_level: Option<String>,
-    #[serde(rename = "fridge_trace_sample_rate")]
-    pub trace_sample_rate: Option<f32>,
-    #[serde(rename = "fridge_error_sample_rate")]
-    pub error_sample_rate: Option<f32>,
-    #[serde(rename = "fridge_broadcast_capacity")]
-    pub broadcast_capacity: Option<usize>,
-    #[serde(rename = "fridge_cors_origin")]
-    pub cors_origin: Option<String>,
-
-    pub sentry_dsn: Option<SecretString>,
-    pub database_url: SecretString,
-}
-
 fn main() -> Result<()> {
     rubenvy::rubenvy_auto()?;
 
-    let config: Config = envy::from_env()?;
+    let config = Config::load()?;
 
     let filter = tracing_subscriber::filter::Targets::default()
         .with_target("reqwest", LevelFilter::OFF)
         .with_target("hyper_util", LevelFilter::OFF)
+        .with_target("rustls", LevelFilter::WARN)
         .with_default(Level::DEBUG);
 
     tracing_subscriber::fmt()
         .with_target(true)
         .with_file(true)
         .with_line_number(true)
-        .with_max_level(config.log_level.as_ref().map_or(Level::DEBUG, |s| {
-            Level::from_str(s).unwrap_or_else(|_| panic!("Invalid value for FRIDGE_LOG_LEVEL: {s}"))
-        }))
+        .with_max_level(
+            config
+                .log_level
+                .as_ref()
+                .and_then(|s| Level::from_str(s).ok())
+                .unwrap_or(Level::DEBUG),
+        )
         .finish()
         .with(sentry::integrations::tracing::layer())
         .with(filter)
@@ -82,83 +108,224 @@ fn main() -> Result<()> {
         None
     };
 
-    tokio::runtime::Builder::new_multi_thread()
+    let runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
-        .build()?
-        .block_on(run(config))
+        .build()?;
+
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        None => runtime.block_on(run(config)),
+        Some("poems") => runtime.block_on(print_poems(config, args.collect())),
+        Some(command) => anyhow::bail!("Unknown command: {command}"),
+    }
 }
 
-async fn broadcast_changes(
-    tx: tokio::sync::broadcast::Sender<PgMagnetUpdate>,
-    token: CancellationToken,
-    mut pg_change_listener: PgListener,
-    broadcast_capacity: usize,
-) -> Result<(), FridgeError> {
-    loop {
-        match pg_change_listener.try_recv().await {
-            Ok(Some(msg)) => {
-                let magnet_update = serde_json::from_str(msg.payload())
-                    .expect("Received invalid JSON from postgres");
-                if tx.len() >= broadcast_capacity {
-                    tracing::error!(
-                        "Potentially dropping queued magnet updates. Consider increasing the \
-                         capacity of the broadcast channel. Current capacity: {broadcast_capacity}"
-                    );
-                }
+/// Prints the poems on a board, or in part of one, for
+/// `fridge-poetry poems [BOARD] [X1 Y1 X2 Y2]`.
+async fn print_poems(config: Config, args: Vec<String>) -> Result<()> {
+    const USAGE: &str = "Usage: fridge-poetry poems [BOARD] [X1 Y1 X2 Y2]";
+
+    let (board_id, window) = match args.as_slice() {
+        [] => (DEFAULT_BOARD, None),
+        [board_id] => (board_id.as_str(), None),
+        [board_id, x1, y1, x2, y2] => {
+            let parse = |coord: &String| coord.parse::<i32>().map_err(|_| anyhow::anyhow!(USAGE));
+            let window = Window {
+                x1: parse(x1)?,
+                y1: parse(y1)?,
+                x2: parse(x2)?,
+                y2: parse(y2)?,
+            };
+            (board_id.as_str(), Some(window))
+        }
+        _ => anyhow::bail!(USAGE),
+    };
+
+    let storage = connect_storage(&config).await?;
+    let Some(bounds) = storage.board_bounds(board_id).await? else {
+        anyhow::bail!("No such board: {board_id}");
+    };
+
+    let shape = Shape::Window(window.unwrap_or(bounds));
+    let magnets = storage.magnets_in(board_id, &shape).await?;
+    for poem in poems::detect(&magnets) {
+        println!("{}\n", poem.text());
+    }
 
-                tracing::trace!("Propagating magnet update to websocket tasks: {magnet_update:#?}");
-                if tx.send(magnet_update).is_err() {
-                    tracing::warn!("Tried broadcasting magnet update but no receivers present.");
+    storage.close().await;
+    Ok(())
+}
+
+async fn broadcast_changes(
+    boards: Boards,
+    magnet_cache: Option<Arc<MagnetCache>>,
+    storage: Arc<dyn Storage>,
+    metrics: Arc<Metrics>,
+    mut changes: Box<dyn ChangeFeed>,
+) {
+    while let Some(change_event) = changes.next().await {
+        match change_event {
+            ChangeEvent::Update(magnet_update) => {
+                // Sessions may query the cache as soon as they see the update
+                if let Some(magnet_cache) = &magnet_cache {
+                    magnet_cache.apply(&magnet_update);
                 }
+                metrics.broadcasts.fetch_add(1, Ordering::Relaxed);
+                boards.broadcast(magnet_update);
             }
-            Ok(None) => {
-                tracing::warn!("Temporarily lost connection to Postgres");
-            }
-            Err(sqlx::Error::PoolClosed) => {
-                return Ok(());
+            ChangeEvent::Gap => {
+                if let Some(magnet_cache) = &magnet_cache
+                    && let Err(e) = magnet_cache.reload(&*storage).await
+                {
+                    tracing::error!("Unable to reload magnet cache: {e}");
+                }
+
+                tracing::info!("Asking sessions to resynchronize after change stream gap");
+                boards.broadcast_gap();
             }
-            Err(e) => {
-                // TODO handle sqlx::Error::Io(std::io::Error::ErrorKind::ConnectionReset)?
-                token.cancel();
-                tracing::error!("{e}");
-                return Err(FridgeError::Sqlx(e));
+        }
+    }
+}
+
+/// Deletes moves once they're too old to be asked for, so that the history
+/// doesn't grow forever.
+async fn prune_history(storage: Arc<dyn Storage>, retention: Duration, token: CancellationToken) {
+    let retention = retention.as_millis().try_into().unwrap_or(i64::MAX);
+
+    let mut ticks = interval(HISTORY_PRUNE_INTERVAL);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        select! {
+            _ = ticks.tick() => {}
+            () = token.cancelled() => break,
+        }
+
+        let oldest = Utc::now().timestamp_millis().saturating_sub(retention);
+        let Some(before) = DateTime::from_timestamp_millis(oldest) else {
+            continue;
+        };
+        match storage.prune_history(before).await {
+            Ok(count) => tracing::debug!("Pruned {count} moves from before {before}"),
+            Err(e) => tracing::error!("Unable to prune history: {e}"),
+        }
+    }
+}
+
+/// Lets go of leases that have run out, so that viewers don't have to time
+/// them out on their own.
+async fn expire_leases(boards: Boards, token: CancellationToken) {
+    let mut ticks = interval(leases::EXPIRY_INTERVAL);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        select! {
+            _ = ticks.tick() => boards.expire_leases(),
+            () = token.cancelled() => break,
+        }
+    }
+}
+
+async fn connect_storage(config: &Config) -> Result<Arc<dyn Storage>> {
+    match config.storage() {
+        StorageKind::Postgres => {
+            let Some(database_url) = config.database_url.as_ref() else {
+                anyhow::bail!("DATABASE_URL must be set when using Postgres storage");
+            };
+
+            // Pool size is ideally ~ core_count * 2 of the Postgres server
+            // https://github.com/brettwooldridge/HikariCP/wiki/About-Pool-Sizing
+            let pool = sqlx::postgres::PgPoolOptions::new()
+                .max_connections(config.db_max_connections())
+                .acquire_timeout(config.db_acquire_timeout())
+                .connect(database_url.expose_secret())
+                .await?;
+
+            sqlx::migrate!().run(&pool).await?;
+
+            Ok(Arc::new(PgStorage::new(pool)))
+        }
+        StorageKind::Memory => {
+            tracing::warn!("Using in-memory storage, nothing will be persisted");
+            let storage = MemoryStorage::new(config.broadcast_capacity());
+            if let Some(seed_file) = config.seed_file.as_ref() {
+                let count = storage.load_seed_file(seed_file)?;
+                tracing::info!("Seeded {count} magnets from {}", seed_file.display());
             }
+            Ok(Arc::new(storage))
         }
     }
 }
 
 async fn run(config: Config) -> Result<()> {
-    // TODO pool size ideally ~ core_count * 2 (of postgres server?)
-    // https://github.com/brettwooldridge/HikariCP/wiki/About-Pool-Sizing
-    // TODO lower acquire timeout?
-    let pool = sqlx::postgres::PgPoolOptions::new()
-        .max_connections(5)
-        .connect(config.database_url.expose_secret())
-        .await?;
-
-    sqlx::migrate!().run(&pool).await?;
+    let rate_limiter = Arc::new(RateLimiter::new(config.rate_limits()));
+    let storage = connect_storage(&config).await?;
 
     let token: CancellationToken = CancellationToken::new();
-    let mut pg_change_listener = PgListener::connect_with(&pool).await?;
-    pg_change_listener.listen("magnet_updates").await?;
+    let changes = storage.changes(token.clone()).await?;
 
-    let broadcast_capacity = config.broadcast_capacity.unwrap_or(100);
-    let tx = broadcast::Sender::new(broadcast_capacity);
+    let boards = Boards::new(config.broadcast_capacity());
+
+    let magnet_cache = if config.magnet_cache.unwrap_or(false) {
+        Some(MagnetCache::load(&*storage).await?)
+    } else {
+        None
+    };
 
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert_path), Some(key_path)) => {
+            let tls = Arc::new(Tls::load(cert_path.clone(), key_path.clone())?);
+            #[cfg(unix)]
+            tokio::spawn(tls.clone().reload_on_hangup());
+            Some(tls)
+        }
+        _ => None,
+    };
+
+    let metrics = Arc::new(Metrics::default());
     let broadcast_changes_task = tokio::task::spawn(broadcast_changes(
-        tx.clone(),
+        boards.clone(),
+        magnet_cache.clone(),
+        storage.clone(),
+        metrics.clone(),
+        changes,
+    ));
+
+    tokio::spawn(expire_leases(boards.clone(), token.clone()));
+    tokio::spawn(prune_history(
+        storage.clone(),
+        config.history_retention(),
         token.clone(),
-        pg_change_listener,
-        broadcast_capacity,
     ));
 
+    if config.allowed_origins.is_none() {
+        tracing::warn!("FRIDGE_ALLOWED_ORIGINS isn't set, accepting connections from any origin");
+    }
+
     let app_state = AppState {
-        postgres: pool,
-        magnet_updates: tx,
+        storage,
+        boards,
+        magnet_cache,
         token: token.clone(),
+        suspended_sessions: Default::default(),
+        rate_limiter,
+        metrics,
+        allowed_origins: Arc::new(AllowedOrigins::new(config.allowed_origins.as_deref())),
+        trusted_proxies: Arc::new(config.trusted_proxies()),
+        broadcast_changes: broadcast_changes_task.abort_handle(),
+        session_timeouts: config.session_timeouts(),
+        history_retention: config.history_retention(),
+        tls,
     };
 
-    let listener = TcpListener::bind("0.0.0.0:8080").await?;
+    if let Some(metrics_addr) = config.metrics_addr {
+        let metrics_listener = TcpListener::bind(metrics_addr).await?;
+        tracing::info!("Serving metrics on {}", metrics_listener.local_addr()?);
+        tokio::spawn(accept_metrics_connections(
+            metrics_listener,
+            app_state.clone(),
+        ));
+    }
+
+    let listener = TcpListener::bind(config.listen_addr()).await?;
     tracing::info!("Listening on {}", listener.local_addr()?);
     let tracker = TaskTracker::new();
     loop {
@@ -189,40 +356,187 @@ async fn run(config: Config) -> Result<()> {
     tracing::info!("Waiting for websocket connections to close");
     tracker.wait().await;
 
-    tracing::info!("Closing Postgres connection pool");
-    app_state.postgres.close().await;
+    tracing::info!("Closing storage");
+    app_state.storage.close().await;
 
     tracing::info!("Waiting for broadcast changes task");
-    broadcast_changes_task.await??;
+    broadcast_changes_task.await?;
 
     Ok(())
 }
 
-async fn accept_connection(stream: TcpStream, state: AppState) {
-    let stream_peer_ip = stream
-        .peer_addr()
-        .map(|a| a.to_string())
-        .unwrap_or_default();
-
-    match tokio_websockets::ServerBuilder::new().accept(stream).await {
-        Ok((request, ws_stream)) => {
-            let peer_addr = request
-                .headers()
-                .get("CF-Connecting-IP")
-                .and_then(|hv| hv.to_str().ok())
-                .and_then(|s| s.parse().ok())
-                .unwrap_or(stream_peer_ip);
+async fn accept_metrics_connections(listener: TcpListener, state: AppState) {
+    loop {
+        select! {
+            accept_result = listener.accept() => {
+                match accept_result {
+                    Ok((stream, _addr)) => {
+                        tokio::spawn(serve_metrics(stream, state.clone()));
+                    }
+                    Err(e) => {
+                        tracing::warn!("Error accepting metrics connection: {e}");
+                    }
+                }
+            }
+            () = state.token.cancelled() => {
+                break;
+            }
+        }
+    }
+}
 
-            let session_id = Uuid::now_v7();
-            tracing::debug!(
-                "Creating new session with session_id: {session_id} for peer: {peer_addr}",
-            );
-            websocket::handle_socket(ws_stream, session_id, state).await;
+async fn serve_metrics(mut stream: TcpStream, state: AppState) {
+    let request = match timeout(
+        handshake::REQUEST_TIMEOUT,
+        handshake::read_request(&mut stream),
+    )
+    .await
+    {
+        Ok(Ok(request)) => request,
+        Ok(Err(e)) => {
+            tracing::debug!("Unable to read metrics request: {e}");
+            return;
         }
+        Err(_) => {
+            tracing::debug!("Timed out waiting for a metrics request");
+            return;
+        }
+    };
+
+    if let Err(e) = routes::serve_metrics(&mut stream, &request, &state).await {
+        tracing::debug!("Unable to respond to metrics request: {e}");
+    }
+}
+
+async fn accept_connection(mut stream: TcpStream, state: AppState) {
+    let stream_peer_ip = match stream.peer_addr() {
+        Ok(addr) => addr.ip(),
         Err(e) => {
-            tracing::warn!("Unable to open websocket connection: {e}");
+            tracing::debug!("Connection closed before it could be accepted: {e}");
+            return;
         }
+    };
+    let stream_peer_ip = match state
+        .trusted_proxies
+        .read_proxy_header(&mut stream, stream_peer_ip)
+        .await
+    {
+        Ok(ip) => ip,
+        Err(e) => {
+            tracing::debug!("Unable to read PROXY header from {stream_peer_ip}: {e}");
+            return;
+        }
+    };
+
+    let mut stream = match &state.tls {
+        Some(tls) => match tls.accept(stream).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                tracing::debug!("TLS handshake failed: {e}");
+                return;
+            }
+        },
+        None => Stream::Plain(stream),
+    };
+
+    let request = match timeout(
+        handshake::REQUEST_TIMEOUT,
+        handshake::read_request(&mut stream),
+    )
+    .await
+    {
+        Ok(Ok(request)) => request,
+        Ok(Err(tokio_websockets::Error::Upgrade(e))) => {
+            tracing::debug!("Rejecting malformed request: {e}");
+            let _ = handshake::reject(&mut stream, StatusCode::BAD_REQUEST).await;
+            return;
+        }
+        Ok(Err(e)) => {
+            tracing::warn!("Unable to read request: {e}");
+            return;
+        }
+        Err(_) => {
+            tracing::debug!("Timed out waiting for a request");
+            return;
+        }
+    };
+
+    let board_id = match Route::of(request.uri().path()) {
+        Route::Board(board_id) if handshake::is_upgrade(&request) => board_id,
+        route => {
+            if let Err(e) = routes::serve(&mut stream, &request, route, &state).await {
+                tracing::debug!("Unable to respond to {}: {e}", request.uri().path());
+            }
+            return;
+        }
+    };
+
+    if !state.allowed_origins.allows(request.headers()) {
+        tracing::debug!(
+            "Rejecting connection from origin {:?}",
+            request.headers().get(http::header::ORIGIN)
+        );
+        state
+            .metrics
+            .rejected_origins
+            .fetch_add(1, Ordering::Relaxed);
+        let _ = handshake::reject(&mut stream, StatusCode::FORBIDDEN).await;
+        return;
+    }
+
+    let board = match state.boards.get(board_id, &*state.storage).await {
+        Ok(Some(board)) => board,
+        Ok(None) => {
+            tracing::debug!("Rejecting connection to unknown board {board_id}");
+            let _ = handshake::reject(&mut stream, StatusCode::NOT_FOUND).await;
+            return;
+        }
+        Err(e) => {
+            tracing::error!("Unable to look up board {board_id}: {e}");
+            let _ = handshake::reject(&mut stream, StatusCode::INTERNAL_SERVER_ERROR).await;
+            return;
+        }
+    };
+
+    let protocol = Protocol::negotiate(request.headers());
+    if let Err(e) = handshake::accept(&mut stream, &request, protocol.subprotocol()).await {
+        tracing::warn!("Unable to open websocket connection: {e}");
+        return;
     }
+    let ws_stream = tokio_websockets::ServerBuilder::new().serve(stream);
+
+    let peer_addr = state
+        .trusted_proxies
+        .client_ip(stream_peer_ip, request.headers());
+
+    let resumed = match ResumeRequest::from_query(request.uri().query()) {
+        Some(resume_request) => state
+            .suspended_sessions
+            .resume(&board.id, &resume_request)
+            .await
+            .map(|session| (resume_request.session_id, session)),
+        None => None,
+    };
+
+    let (session_id, resumed) = match resumed {
+        Some((session_id, session)) => {
+            tracing::debug!("Resuming session_id: {session_id} for peer: {peer_addr}");
+            (session_id, Some(session))
+        }
+        None => {
+            let session_id = Uuid::now_v7();
+            tracing::debug!(
+                "Creating new session with session_id: {session_id} for peer: {peer_addr} using \
+                 {protocol:?} protocol",
+            );
+            (session_id, None)
+        }
+    };
+
+    websocket::handle_socket(
+        ws_stream, protocol, board, session_id, peer_addr, resumed, state,
+    )
+    .await;
 }
 
 async fn shutdown_signal() {
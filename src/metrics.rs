@@ -0,0 +1,258 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    sync::{
+        Mutex,
+        atomic::{AtomicI64, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use crate::{board::Boards, error::FridgeError, geometry::Shape, rate_limit::RequestKind};
+
+/// Upper bounds of the query latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// Process-wide counters, shared through `AppState` and served to Prometheus
+/// from `/metrics` on `FRIDGE_METRICS_ADDR`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Sessions with an open connection
+    active_sessions: AtomicI64,
+    /// Sessions that have been opened, resumed or not
+    connects: AtomicU64,
+    /// Sessions that have been closed, by why they were closed
+    disconnects: Mutex<BTreeMap<&'static str, u64>>,
+    /// Times a session fell behind its queue of updates and was resynced
+    pub lagged_sessions: AtomicU64,
+    /// Updates skipped by lagging sessions
+    pub skipped_updates: AtomicU64,
+    /// Changes received from storage to pass on to sessions
+    pub broadcasts: AtomicU64,
+    /// Moves made by sessions on this server, including undos
+    pub magnets_moved: AtomicU64,
+    /// WebSocket upgrades refused because of where the page was from
+    pub rejected_origins: AtomicU64,
+    window_rate_limited: AtomicU64,
+    move_rate_limited: AtomicU64,
+    window_queries: Histogram,
+    polygon_queries: Histogram,
+}
+
+impl Metrics {
+    pub fn record_lag(&self, skipped: u64) {
+        self.lagged_sessions.fetch_add(1, Ordering::Relaxed);
+        self.skipped_updates.fetch_add(skipped, Ordering::Relaxed);
+    }
+
+    /// Counts a session as active until the returned guard is dropped.
+    pub fn session_started(&self) -> ActiveSession<'_> {
+        self.connects.fetch_add(1, Ordering::Relaxed);
+        self.active_sessions.fetch_add(1, Ordering::Relaxed);
+        ActiveSession { metrics: self }
+    }
+
+    pub fn record_disconnect(&self, error: &FridgeError) {
+        *self
+            .disconnects
+            .lock()
+            .unwrap()
+            .entry(error.reason())
+            .or_default() += 1;
+    }
+
+    pub fn record_rate_limited(&self, request_kind: RequestKind) {
+        match request_kind {
+            RequestKind::WindowUpdate => &self.window_rate_limited,
+            RequestKind::Move => &self.move_rate_limited,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_query(&self, shape: &Shape, elapsed: Duration) {
+        match shape {
+            Shape::Window(_) => &self.window_queries,
+            Shape::Polygon(_) => &self.polygon_queries,
+        }
+        .observe(elapsed);
+    }
+
+    /// Formats everything in Prometheus' text exposition format.
+    pub fn render(&self, boards: &Boards) -> String {
+        let mut out = String::new();
+
+        gauge(
+            &mut out,
+            "fridge_active_sessions",
+            "Sessions with an open connection",
+            self.active_sessions.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "fridge_connects_total",
+            "Sessions opened",
+            self.connects.load(Ordering::Relaxed),
+        );
+
+        header(
+            &mut out,
+            "fridge_disconnects_total",
+            "counter",
+            "Sessions closed, by reason",
+        );
+        for (reason, count) in self.disconnects.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "fridge_disconnects_total{{reason=\"{reason}\"}} {count}"
+            );
+        }
+
+        let queue_lengths = boards.queue_lengths();
+        gauge(
+            &mut out,
+            "fridge_broadcast_queued",
+            "Updates waiting to be sent to sessions",
+            queue_lengths.iter().sum::<usize>(),
+        );
+        gauge(
+            &mut out,
+            "fridge_broadcast_queue_max",
+            "Updates waiting to be sent to the session furthest behind",
+            queue_lengths.iter().max().copied().unwrap_or(0),
+        );
+        counter(
+            &mut out,
+            "fridge_broadcasts_total",
+            "Changes received from storage",
+            self.broadcasts.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "fridge_lagged_sessions_total",
+            "Times a session fell too far behind and was resynchronized",
+            self.lagged_sessions.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "fridge_skipped_updates_total",
+            "Updates skipped by sessions that fell behind",
+            self.skipped_updates.load(Ordering::Relaxed),
+        );
+
+        header(
+            &mut out,
+            "fridge_magnet_query_seconds",
+            "histogram",
+            "Time taken to look up the magnets in a shape",
+        );
+        self.window_queries
+            .render(&mut out, "fridge_magnet_query_seconds", "window");
+        self.polygon_queries
+            .render(&mut out, "fridge_magnet_query_seconds", "polygon");
+
+        counter(
+            &mut out,
+            "fridge_magnets_moved_total",
+            "Magnets moved by sessions on this server",
+            self.magnets_moved.load(Ordering::Relaxed),
+        );
+
+        counter(
+            &mut out,
+            "fridge_rejected_origins_total",
+            "WebSocket upgrades refused for coming from a page on another origin",
+            self.rejected_origins.load(Ordering::Relaxed),
+        );
+
+        header(
+            &mut out,
+            "fridge_rate_limited_total",
+            "counter",
+            "Requests rejected for exceeding rate limits, by kind",
+        );
+        for (kind, count) in [
+            ("window_update", &self.window_rate_limited),
+            ("move", &self.move_rate_limited),
+        ] {
+            let _ = writeln!(
+                out,
+                "fridge_rate_limited_total{{kind=\"{kind}\"}} {}",
+                count.load(Ordering::Relaxed)
+            );
+        }
+
+        out
+    }
+}
+
+/// Keeps a session counted as active.
+#[derive(Debug)]
+pub struct ActiveSession<'a> {
+    metrics: &'a Metrics,
+}
+
+impl Drop for ActiveSession<'_> {
+    fn drop(&mut self) {
+        self.metrics.active_sessions.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Observations in each bucket, not counting smaller buckets, with the
+    /// last one for everything above the largest bound
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(
+            elapsed.as_micros().try_into().unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+
+    fn render(&self, out: &mut String, name: &str, shape: &str) {
+        let mut count = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            count += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{shape=\"{shape}\",le=\"{bound}\"}} {count}"
+            );
+        }
+        count += self.buckets[LATENCY_BUCKETS.len()].load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{shape=\"{shape}\",le=\"+Inf\"}} {count}"
+        );
+
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{name}_sum{{shape=\"{shape}\"}} {sum}");
+        let _ = writeln!(out, "{name}_count{{shape=\"{shape}\"}} {count}");
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, "counter", help);
+    let _ = writeln!(out, "{name} {value}");
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: impl std::fmt::Display) {
+    header(out, name, "gauge", help);
+    let _ = writeln!(out, "{name} {value}");
+}
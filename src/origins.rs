@@ -0,0 +1,207 @@
+use http::{HeaderMap, header::ORIGIN};
+
+/// Origins whose pages can open WebSockets and read from the HTTP endpoints,
+/// as `scheme://host[:port]` with an optional `*.` in front of the host to
+/// allow every subdomain, or `*` to allow any origin.
+#[derive(Debug, Default)]
+pub struct AllowedOrigins {
+    /// Allowing any origin when unset, so deployments that haven't
+    /// configured this keep working
+    patterns: Option<Vec<String>>,
+}
+
+impl AllowedOrigins {
+    pub fn new(patterns: Option<&[String]>) -> AllowedOrigins {
+        AllowedOrigins {
+            patterns: patterns.map(|patterns| {
+                patterns
+                    .iter()
+                    .map(|pattern| pattern.trim().trim_end_matches('/').to_lowercase())
+                    .collect()
+            }),
+        }
+    }
+
+    /// Whether a request can go ahead. Only browsers send `Origin`, so
+    /// requests without one are let through, as anything else could leave it
+    /// out anyway.
+    pub fn allows(&self, headers: &HeaderMap) -> bool {
+        let Some(patterns) = &self.patterns else {
+            return true;
+        };
+        match headers.get(ORIGIN) {
+            None => true,
+            Some(origin) => origin
+                .to_str()
+                .is_ok_and(|origin| patterns.iter().any(|pattern| matches(pattern, origin))),
+        }
+    }
+
+    /// What to send as `Access-Control-Allow-Origin`, if anything.
+    pub fn cors_origin<'a>(&self, headers: &'a HeaderMap) -> Option<&'a str> {
+        self.patterns.as_ref()?;
+        let origin = headers.get(ORIGIN)?.to_str().ok()?;
+        self.allows(headers).then_some(origin)
+    }
+}
+
+fn matches(pattern: &str, origin: &str) -> bool {
+    if pattern == "*" {
+        return true;
+    }
+
+    let origin = origin.to_lowercase();
+    let Some((scheme, domain)) = pattern.split_once("://*.") else {
+        return origin == pattern;
+    };
+
+    origin
+        .strip_prefix(scheme)
+        .and_then(|origin| origin.strip_prefix("://"))
+        .and_then(|host| host.strip_suffix(domain))
+        .and_then(|subdomain| subdomain.strip_suffix('.'))
+        .is_some_and(|subdomain| {
+            !subdomain.is_empty()
+                && subdomain
+                    .split('.')
+                    .all(|label| !label.is_empty() && label.chars().all(is_host_char))
+        })
+}
+
+fn is_host_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-'
+}
+
+/// Checks that an allowlist entry is something an `Origin` header could
+/// match.
+pub fn validate_pattern(pattern: &str) -> Result<(), String> {
+    let invalid = |reason: &str| Err(format!("Invalid allowed origin {pattern:?}: {reason}"));
+
+    let pattern = pattern.trim().trim_end_matches('/');
+    if pattern == "*" {
+        return Ok(());
+    }
+    let Some((scheme, host)) = pattern.split_once("://") else {
+        return invalid("expected scheme://host");
+    };
+    if !matches!(scheme.to_lowercase().as_str(), "http" | "https") {
+        return invalid("scheme must be http or https");
+    }
+
+    let host = host.strip_prefix("*.").unwrap_or(host);
+    let (host, port) = match host.rsplit_once(':') {
+        Some((host, port)) => (host, Some(port)),
+        None => (host, None),
+    };
+    if host.is_empty()
+        || !host
+            .split('.')
+            .all(|label| !label.is_empty() && label.chars().all(is_host_char))
+    {
+        return invalid("host can only have a leading *. wildcard and no path");
+    }
+    if port.is_some_and(|port| port.parse::<u16>().is_err()) {
+        return invalid("port must be a number");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+
+    use super::*;
+
+    fn allowed(patterns: &[&str]) -> AllowedOrigins {
+        let patterns: Vec<String> = patterns.iter().map(ToString::to_string).collect();
+        AllowedOrigins::new(Some(&patterns))
+    }
+
+    fn from(origin: &str) -> HeaderMap {
+        HeaderMap::from_iter([(ORIGIN, HeaderValue::from_str(origin).unwrap())])
+    }
+
+    #[test]
+    fn exact_patterns_match_only_that_origin() {
+        assert!(matches("https://fridgepoem.com", "https://fridgepoem.com"));
+        assert!(matches("https://fridgepoem.com", "HTTPS://FridgePoem.com"));
+        assert!(!matches("https://fridgepoem.com", "http://fridgepoem.com"));
+        assert!(!matches(
+            "https://fridgepoem.com",
+            "https://fridgepoem.com:8443"
+        ));
+        assert!(!matches(
+            "https://fridgepoem.com",
+            "https://www.fridgepoem.com"
+        ));
+        assert!(!matches(
+            "https://fridgepoem.com",
+            "https://fridgepoem.com.evil.com"
+        ));
+    }
+
+    #[test]
+    fn wildcards_match_subdomains_but_not_the_domain_itself() {
+        let pattern = "https://*.fridgepoem.com";
+        assert!(matches(pattern, "https://www.fridgepoem.com"));
+        assert!(matches(pattern, "https://a.b.fridgepoem.com"));
+        assert!(!matches(pattern, "https://fridgepoem.com"));
+        assert!(!matches(pattern, "https://.fridgepoem.com"));
+        assert!(!matches(pattern, "https://evilfridgepoem.com"));
+        assert!(!matches(pattern, "https://www.fridgepoem.com.evil.com"));
+        assert!(!matches(pattern, "https://evil.com/.fridgepoem.com"));
+        assert!(!matches(pattern, "http://www.fridgepoem.com"));
+    }
+
+    #[test]
+    fn star_matches_any_origin() {
+        assert!(validate_pattern("*").is_ok());
+        assert!(matches("*", "https://fridgepoem.com"));
+        assert!(matches("*", "null"));
+
+        let origins = allowed(&["*"]);
+        assert!(origins.allows(&from("https://anywhere.example")));
+        assert_eq!(
+            origins.cors_origin(&from("https://anywhere.example")),
+            Some("https://anywhere.example")
+        );
+    }
+
+    #[test]
+    fn requests_without_an_origin_are_allowed() {
+        let origins = allowed(&["https://fridgepoem.com"]);
+        assert!(origins.allows(&HeaderMap::new()));
+        assert!(!origins.allows(&from("https://elsewhere.example")));
+        assert_eq!(origins.cors_origin(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn only_origins_are_valid_patterns() {
+        for pattern in [
+            "https://fridgepoem.com",
+            "https://fridgepoem.com/",
+            "http://localhost:5173",
+            "https://*.fridgepoem.com",
+        ] {
+            assert!(
+                validate_pattern(pattern).is_ok(),
+                "{pattern} should be valid"
+            );
+        }
+        for pattern in [
+            "fridgepoem.com",
+            "ftp://fridgepoem.com",
+            "https://fridgepoem.com/poems",
+            "https://www.*.fridgepoem.com",
+            "https://*",
+            "https://fridgepoem.com:http",
+            "**",
+        ] {
+            assert!(
+                validate_pattern(pattern).is_err(),
+                "{pattern} shouldn't be valid"
+            );
+        }
+    }
+}
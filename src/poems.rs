@@ -0,0 +1,332 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::protocol::Magnet;
+
+// Rough size of a magnet, going by the frontend's 16px Georgia with 5px of
+// padding and a 1px border. Magnets are positioned by their top left corner,
+// and y goes up.
+const CHAR_WIDTH: f64 = 8.0;
+const MAGNET_PADDING: f64 = 12.0;
+const MAGNET_HEIGHT: f64 = 30.0;
+
+/// Widest gap between two words on the same line
+const MAX_WORD_GAP: f64 = 30.0;
+/// How far two words can overlap and still be read one after the other
+const MAX_WORD_OVERLAP: f64 = 10.0;
+/// How far a word can sit above or below the line it's continuing
+const MAX_BASELINE_OFFSET: f64 = MAGNET_HEIGHT / 2.0;
+/// Magnets tilted further than this, in degrees, aren't read as part of a line
+const MAX_TILT: i32 = 20;
+/// Largest difference in tilt between neighbouring words
+const MAX_TILT_DIFFERENCE: i32 = 15;
+/// Widest gap between the lines of one poem
+const MAX_LINE_GAP: f64 = MAGNET_HEIGHT;
+
+/// Size of the grid cells used to find neighbouring words. Big enough that
+/// the next word is always in a neighbouring cell, barring very long words.
+const CELL_SIZE: f64 = 256.0;
+
+/// Lines of words read top to bottom.
+#[derive(Debug, Serialize)]
+pub struct Poem {
+    pub lines: Vec<Line>,
+}
+
+impl Poem {
+    pub fn text(&self) -> String {
+        self.lines
+            .iter()
+            .map(|line| line.text.as_str())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Words read left to right.
+#[derive(Debug, Serialize)]
+pub struct Line {
+    pub ids: Vec<i32>,
+    pub text: String,
+}
+
+/// A word as it shows up on the fridge.
+#[derive(Debug)]
+struct Word<'a> {
+    id: i32,
+    text: &'a str,
+    left: f64,
+    right: f64,
+    center_y: f64,
+    tilt: i32,
+}
+
+impl<'a> Word<'a> {
+    fn new(magnet: &'a Magnet) -> Option<Word<'a>> {
+        let text = visible_text(&magnet.word)?;
+
+        let tilt = match magnet.rotation.rem_euclid(360) {
+            rotation if rotation > 180 => rotation - 360,
+            rotation => rotation,
+        };
+        if tilt.abs() > MAX_TILT {
+            return None;
+        }
+
+        let width = text.chars().count() as f64 * CHAR_WIDTH + MAGNET_PADDING;
+        Some(Word {
+            id: magnet.id,
+            text,
+            left: f64::from(magnet.x),
+            right: f64::from(magnet.x) + width,
+            center_y: f64::from(magnet.y) - MAGNET_HEIGHT / 2.0,
+            tilt,
+        })
+    }
+
+    fn cell(&self) -> (i64, i64) {
+        (
+            (self.left / CELL_SIZE).floor() as i64,
+            (self.center_y / CELL_SIZE).floor() as i64,
+        )
+    }
+
+    /// How well `next` continues a line after this word, lower being better,
+    /// or `None` if it doesn't.
+    fn continued_by(&self, next: &Word) -> Option<f64> {
+        let gap = next.left - self.right;
+        if !(-MAX_WORD_OVERLAP..=MAX_WORD_GAP).contains(&gap)
+            || (self.tilt - next.tilt).abs() > MAX_TILT_DIFFERENCE
+        {
+            return None;
+        }
+
+        // Tilted lines slope down to the right, as rotations are clockwise
+        let slope = f64::from(self.tilt + next.tilt).to_radians() / 2.0;
+        let run = (next.left + next.right - self.left - self.right) / 2.0;
+        let expected_y = self.center_y - run * slope.tan();
+        let offset = (next.center_y - expected_y).abs();
+        if offset > MAX_BASELINE_OFFSET {
+            return None;
+        }
+
+        Some(gap.abs() + offset)
+    }
+}
+
+/// What a word says, without any markup. Words that are only markup, like
+/// images, don't say anything.
+fn visible_text(word: &str) -> Option<&str> {
+    let text = match word.find('<') {
+        None => word,
+        Some(_) => {
+            // Only simple `<a ...>text</a>` style wrapping is worth reading
+            let start = word.find('>')? + 1;
+            let end = word[start..].find('<')? + start;
+            &word[start..end]
+        }
+    };
+
+    let text = text.trim();
+    (!text.is_empty()).then_some(text)
+}
+
+/// Finds the lines of at least two words among `magnets`, and groups lines
+/// that are stacked on top of each other into poems. Poems come in reading
+/// order, top to bottom and then left to right.
+pub fn detect(magnets: &[Magnet]) -> Vec<Poem> {
+    let words: Vec<Word> = magnets.iter().filter_map(Word::new).collect();
+
+    let mut grid: HashMap<(i64, i64), Vec<usize>> = HashMap::new();
+    for (i, word) in words.iter().enumerate() {
+        grid.entry(word.cell()).or_default().push(i);
+    }
+
+    // Every pair of words that could be read one after the other, best first
+    let mut candidates = Vec::new();
+    for (i, word) in words.iter().enumerate() {
+        let (cell_x, cell_y) = word.cell();
+        for x in cell_x - 1..=cell_x + 1 {
+            for y in cell_y - 1..=cell_y + 1 {
+                for &j in grid.get(&(x, y)).into_iter().flatten() {
+                    if i != j
+                        && let Some(score) = word.continued_by(&words[j])
+                    {
+                        candidates.push((score, i, j));
+                    }
+                }
+            }
+        }
+    }
+    candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let mut next: Vec<Option<usize>> = vec![None; words.len()];
+    let mut previous: Vec<Option<usize>> = vec![None; words.len()];
+    for (_, i, j) in candidates {
+        // Lines can't loop back on themselves, as each word starts further
+        // right than the one before it
+        if next[i].is_none() && previous[j].is_none() {
+            next[i] = Some(j);
+            previous[j] = Some(i);
+        }
+    }
+
+    let mut lines: Vec<Vec<usize>> = (0..words.len())
+        .filter(|&i| previous[i].is_none() && next[i].is_some())
+        .map(|first| std::iter::successors(Some(first), |&i| next[i]).collect())
+        .collect();
+    lines.sort_by(|a, b| {
+        let (a, b) = (&words[a[0]], &words[b[0]]);
+        b.center_y
+            .total_cmp(&a.center_y)
+            .then(a.left.total_cmp(&b.left))
+    });
+
+    stack_lines(&words, lines)
+}
+
+/// Groups lines, sorted top to bottom, into poems.
+fn stack_lines(words: &[Word], lines: Vec<Vec<usize>>) -> Vec<Poem> {
+    let extent = |line: &[usize]| {
+        let left = line.iter().map(|&i| words[i].left).fold(f64::MAX, f64::min);
+        let right = line
+            .iter()
+            .map(|&i| words[i].right)
+            .fold(f64::MIN, f64::max);
+        (left, right, words[line[0]].center_y)
+    };
+
+    // Which poem each line belongs to, found by joining every line with the
+    // ones just below it
+    let mut poem_of: Vec<usize> = (0..lines.len()).collect();
+    fn root(poem_of: &mut [usize], mut i: usize) -> usize {
+        while poem_of[i] != i {
+            poem_of[i] = poem_of[poem_of[i]];
+            i = poem_of[i];
+        }
+        i
+    }
+
+    let extents: Vec<_> = lines.iter().map(|line| extent(line)).collect();
+    for (i, &(left, right, center_y)) in extents.iter().enumerate() {
+        for (j, &(below_left, below_right, below_center_y)) in
+            extents.iter().enumerate().skip(i + 1)
+        {
+            let gap = center_y - below_center_y - MAGNET_HEIGHT;
+            if gap > MAX_LINE_GAP {
+                break;
+            }
+            if below_left <= right && left <= below_right {
+                let (a, b) = (root(&mut poem_of, i), root(&mut poem_of, j));
+                poem_of[a.max(b)] = a.min(b);
+            }
+        }
+    }
+
+    // Poems start at their topmost line, which keeps them in reading order
+    let mut poems: Vec<Poem> = Vec::new();
+    let mut poem_index: HashMap<usize, usize> = HashMap::new();
+    for (i, line) in lines.into_iter().enumerate() {
+        let poem = *poem_index.entry(root(&mut poem_of, i)).or_insert_with(|| {
+            poems.push(Poem { lines: Vec::new() });
+            poems.len() - 1
+        });
+        poems[poem].lines.push(Line {
+            ids: line.iter().map(|&i| words[i].id).collect(),
+            text: line
+                .iter()
+                .map(|&i| words[i].text)
+                .collect::<Vec<_>>()
+                .join(" "),
+        });
+    }
+    poems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn magnet(id: i32, word: &str, x: i32, y: i32, rotation: i32) -> Magnet {
+        Magnet {
+            id,
+            x,
+            y,
+            rotation,
+            z_index: i64::from(id),
+            word: word.to_string(),
+        }
+    }
+
+    fn texts(poems: &[Poem]) -> Vec<String> {
+        poems.iter().map(Poem::text).collect()
+    }
+
+    #[test]
+    fn reads_a_line_left_to_right() {
+        // "hello" is 52 wide, leaving a gap of 8 before "world"
+        let magnets = [magnet(2, "world", 60, 0, 0), magnet(1, "hello", 0, 0, 0)];
+
+        let poems = detect(&magnets);
+        assert_eq!(texts(&poems), ["hello world"]);
+        assert_eq!(poems[0].lines[0].ids, [1, 2]);
+    }
+
+    #[test]
+    fn stacks_lines_top_to_bottom() {
+        let magnets = [
+            magnet(3, "the", 10, -35, 0),
+            magnet(4, "fridge", 50, -35, 0),
+            magnet(1, "on", 0, 0, 0),
+            magnet(2, "top", 36, 0, 0),
+        ];
+
+        assert_eq!(texts(&detect(&magnets)), ["on top\nthe fridge"]);
+    }
+
+    #[test]
+    fn separates_poems_that_are_apart() {
+        let magnets = [
+            magnet(1, "far", 0, 0, 0),
+            magnet(2, "away", 40, 0, 0),
+            magnet(3, "over", 0, -500, 0),
+            magnet(4, "here", 50, -500, 0),
+        ];
+
+        assert_eq!(texts(&detect(&magnets)), ["far away", "over here"]);
+    }
+
+    #[test]
+    fn follows_gently_tilted_lines() {
+        let magnets = [
+            magnet(1, "tilted", 0, 0, 10),
+            magnet(2, "line", 70, -20, 10),
+        ];
+
+        assert_eq!(texts(&detect(&magnets)), ["tilted line"]);
+    }
+
+    #[test]
+    fn ignores_words_that_do_not_line_up() {
+        // Too far apart, too far above, and tipped over
+        let too_far = [magnet(1, "a", 0, 0, 0), magnet(2, "b", 100, 0, 0)];
+        let too_high = [magnet(1, "a", 0, 0, 0), magnet(2, "b", 22, 20, 0)];
+        let tipped = [magnet(1, "a", 0, 0, 0), magnet(2, "b", 22, 0, 90)];
+
+        for magnets in [too_far, too_high, tipped] {
+            assert!(detect(&magnets).is_empty(), "{magnets:?}");
+        }
+    }
+
+    #[test]
+    fn reads_linked_words_and_skips_images() {
+        let magnets = [
+            magnet(1, "<a href=\"https://example.com\">click</a>", 0, 0, 0),
+            magnet(2, "<img src=\"cat.png\">", 52, 0, 0),
+            magnet(3, "me", 60, 0, 0),
+        ];
+
+        assert_eq!(texts(&detect(&magnets)), ["click me"]);
+    }
+}
@@ -0,0 +1,10 @@
+/// Where someone is pointing on a board. Viewers are told apart by their
+/// subscription rather than their session, which they shouldn't learn.
+#[derive(Clone, Debug)]
+pub struct CursorUpdate {
+    pub viewer: u64,
+    /// Where the cursor was last seen, if anywhere
+    pub old: Option<(i32, i32)>,
+    /// Where the cursor is now, or `None` once it's gone
+    pub new: Option<(i32, i32)>,
+}
@@ -0,0 +1,465 @@
+use http::{HeaderMap, header::SEC_WEBSOCKET_PROTOCOL};
+use serde::{Deserialize, Serialize};
+
+use crate::{geometry::Window, poems::Poem};
+
+/// Wire format spoken on a connection, chosen during the upgrade handshake.
+///
+/// `Legacy` is the original untagged format, where the kind of a message is
+/// inferred from its shape. It is used for clients that don't request a
+/// subprotocol so that older frontends keep working.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Legacy,
+    V2,
+}
+
+impl Protocol {
+    const V2_SUBPROTOCOL: &str = "fridge-poetry.v2";
+
+    pub fn negotiate(headers: &HeaderMap) -> Protocol {
+        let offers_v2 = headers
+            .get_all(SEC_WEBSOCKET_PROTOCOL)
+            .iter()
+            .filter_map(|hv| hv.to_str().ok())
+            .flat_map(|s| s.split(','))
+            .any(|offered| offered.trim() == Self::V2_SUBPROTOCOL);
+
+        if offers_v2 {
+            Protocol::V2
+        } else {
+            Protocol::Legacy
+        }
+    }
+
+    /// Value for the `Sec-WebSocket-Protocol` response header, if any.
+    pub fn subprotocol(self) -> Option<&'static str> {
+        match self {
+            Protocol::Legacy => None,
+            Protocol::V2 => Some(Self::V2_SUBPROTOCOL),
+        }
+    }
+
+    /// Serializes an update for the wire. Returns `None` if the update has no
+    /// representation in this version of the protocol and should be skipped.
+    pub fn encode(self, update: &MagnetUpdate) -> Option<Vec<u8>> {
+        match self {
+            Protocol::Legacy => {
+                let legacy_update = LegacyMagnetUpdate::from_update(update)?;
+                Some(rmp_serde::to_vec(&legacy_update).unwrap())
+            }
+            Protocol::V2 => Some(rmp_serde::to_vec_named(update).unwrap()),
+        }
+    }
+
+    pub fn decode(self, payload: &[u8]) -> Result<ClientRequest, rmp_serde::decode::Error> {
+        match self {
+            Protocol::Legacy => {
+                rmp_serde::from_slice::<LegacyClientUpdate>(payload).map(|update| ClientRequest {
+                    request_id: None,
+                    update: update.into(),
+                })
+            }
+            Protocol::V2 => rmp_serde::from_slice(payload),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Magnet {
+    pub id: i32,
+    pub x: i32,
+    pub y: i32,
+    pub rotation: i32,
+    pub z_index: i64,
+    pub word: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LocationUpdate {
+    pub id: i32,
+    pub x: i32,
+    pub y: i32,
+    pub rotation: i32,
+    pub z_index: i64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MagnetUpdate {
+    Create(Magnet),
+    Move(LocationUpdate),
+    Remove {
+        id: i32,
+    },
+    CanvasUpdate {
+        magnets: Vec<Magnet>,
+    },
+    HistoryUpdate {
+        at: i64,
+        magnets: Vec<Magnet>,
+    },
+    /// A request was dropped for going over the rate limits, and can be
+    /// retried in `retry_after_ms`. `id` is the magnet that didn't move, so
+    /// that the client can put it back.
+    RateLimited {
+        request_id: Option<u32>,
+        id: Option<i32>,
+        retry_after_ms: u64,
+    },
+    /// Someone else is holding a magnet, until they let go or for
+    /// `expires_in_ms`, whichever comes first. Holders renew by grabbing again.
+    Held {
+        id: i32,
+        expires_in_ms: u64,
+    },
+    /// Nobody is holding a magnet anymore, because they let go, went away or
+    /// let their hold expire
+    Released {
+        id: i32,
+    },
+    /// Someone else is dragging a magnet, and has it at `(x, y)` for now
+    Dragged {
+        id: i32,
+        x: i32,
+        y: i32,
+        rotation: i32,
+    },
+    /// Someone else's cursor, which has moved to `(x, y)`
+    Cursor {
+        viewer: u64,
+        x: i32,
+        y: i32,
+    },
+    /// Someone else's cursor has left the window, or they've left altogether
+    CursorGone {
+        viewer: u64,
+    },
+    /// How many other people are looking at or near the window
+    Viewers {
+        count: usize,
+    },
+    /// The poems written in the window
+    Poems {
+        poems: Vec<Poem>,
+    },
+    /// A saved poem can be found at `/poems/{slug}`
+    PoemSaved {
+        slug: String,
+    },
+    /// A request with a `request_id` went through. Moves carry the `z_index`
+    /// the magnet ended up with. Undos carry how many magnets were put back,
+    /// which is fewer than asked for when some have been moved or held by
+    /// someone else since.
+    Ack {
+        request_id: u32,
+        z_index: Option<i64>,
+        reverted: Option<usize>,
+    },
+    /// A request was turned down and nothing changed. `id` is the magnet that
+    /// didn't move, as with `RateLimited`.
+    Error {
+        request_id: Option<u32>,
+        id: Option<i32>,
+        code: ErrorCode,
+    },
+    SessionIdUpdate {
+        session_id: String,
+        // Presented when reconnecting to resume this session
+        resume_token: String,
+        resumed: bool,
+    },
+}
+
+/// Why a request was turned down.
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// A window, move or point in time that's outside of what's allowed
+    OutOfBounds,
+    /// The magnet isn't on this board
+    NotFound,
+    /// The magnet was moved by someone else after the `z_index` the client
+    /// sent. The move that got there first is on its way.
+    Conflict,
+    /// Someone else is holding the magnet
+    Held,
+    /// The session is already holding as many magnets as it can, and has to
+    /// let go of one first
+    TooManyHeld,
+    /// Only the session holding a magnet can drag it
+    NotHeld,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum LegacyMagnetUpdate<'a> {
+    Create(&'a Magnet),
+    Move(&'a LocationUpdate),
+    Remove(i32),
+    CanvasUpdate(&'a [Magnet]),
+    SessionIdUpdate(&'a str),
+}
+
+impl<'a> LegacyMagnetUpdate<'a> {
+    fn from_update(update: &'a MagnetUpdate) -> Option<Self> {
+        Some(match update {
+            MagnetUpdate::Create(magnet) => LegacyMagnetUpdate::Create(magnet),
+            MagnetUpdate::Move(location) => LegacyMagnetUpdate::Move(location),
+            MagnetUpdate::Remove { id } => LegacyMagnetUpdate::Remove(*id),
+            MagnetUpdate::CanvasUpdate { magnets } => LegacyMagnetUpdate::CanvasUpdate(magnets),
+            MagnetUpdate::HistoryUpdate { .. }
+            | MagnetUpdate::RateLimited { .. }
+            | MagnetUpdate::Held { .. }
+            | MagnetUpdate::Released { .. }
+            | MagnetUpdate::Dragged { .. }
+            | MagnetUpdate::Cursor { .. }
+            | MagnetUpdate::CursorGone { .. }
+            | MagnetUpdate::Viewers { .. }
+            | MagnetUpdate::Poems { .. }
+            | MagnetUpdate::PoemSaved { .. }
+            | MagnetUpdate::Ack { .. }
+            | MagnetUpdate::Error { .. } => return None,
+            MagnetUpdate::SessionIdUpdate { session_id, .. } => {
+                LegacyMagnetUpdate::SessionIdUpdate(session_id)
+            }
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClientMagnetUpdate {
+    pub id: i32,
+    pub x: i32,
+    pub y: i32,
+    pub rotation: i32,
+    /// The `z_index` the client last saw the magnet at. If it has been moved
+    /// since, the move is turned down rather than overwriting the other one.
+    #[serde(default)]
+    pub z_index: Option<i64>,
+}
+
+/// Whether a magnet can be put at `(x, y)` with `rotation` by a client looking
+/// at `window`.
+pub fn is_valid_placement(
+    id: i32,
+    x: i32,
+    y: i32,
+    rotation: i32,
+    window: &Window,
+    bounds: &Window,
+) -> bool {
+    const MAX_MAGNET_ID: i32 = 22_000_000;
+
+    if id > MAX_MAGNET_ID {
+        tracing::trace!("Invalid id: {id}");
+        return false;
+    }
+
+    if !(-360..=360).contains(&rotation) {
+        tracing::trace!("Invalid rotation: {rotation}");
+        return false;
+    }
+
+    if !(window.x1 - 100..=window.x2 + 100).contains(&x)
+        || !(window.y1 - 100..=window.y2 + 100).contains(&y)
+    {
+        tracing::trace!("Invalid location outside window bounds: ({x}, {y})");
+        return false;
+    }
+
+    if !bounds.contains(x, y) {
+        tracing::trace!("Invalid update outside world bounds: ({x}, {y})");
+        return false;
+    }
+
+    true
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientUpdate {
+    Window(Window),
+    Magnet(ClientMagnetUpdate),
+    /// Takes back this session's last `count` moves, the `Ack` says how many
+    /// magnets were put back
+    Undo {
+        count: usize,
+    },
+    /// Asks for the current window as it was at `at`, in milliseconds since
+    /// the Unix epoch. Times older than the server's history retention are
+    /// out of bounds.
+    History {
+        at: i64,
+    },
+    /// Starts dragging a magnet, so that nobody else can move it for a while.
+    /// Only magnets in the window can be grabbed.
+    Grab {
+        id: i32,
+    },
+    Release {
+        id: i32,
+    },
+    /// Shows everyone else where a held magnet is being dragged, without moving
+    /// it.
+    /// Too frequent drags are dropped, the drag should end with a `Magnet`.
+    Drag {
+        id: i32,
+        x: i32,
+        y: i32,
+        rotation: i32,
+    },
+    /// Shows everyone looking at `(x, y)` where this session is pointing.
+    /// Throttled like drags.
+    Cursor {
+        x: i32,
+        y: i32,
+    },
+    /// Asks how many other people are looking at or near the window
+    Viewers,
+    /// Asks for the poems written in the window
+    Poems,
+    /// Keeps the magnets in a window as they are now, to be shared
+    SavePoem(Window),
+}
+
+/// A `ClientUpdate` along with the id the client wants it acknowledged with,
+/// if any.
+#[derive(Debug, Deserialize)]
+pub struct ClientRequest {
+    #[serde(default)]
+    pub request_id: Option<u32>,
+    #[serde(flatten)]
+    pub update: ClientUpdate,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum LegacyClientUpdate {
+    Window(Window),
+    Magnet(LegacyClientMagnetUpdate),
+}
+
+impl From<LegacyClientUpdate> for ClientUpdate {
+    fn from(update: LegacyClientUpdate) -> Self {
+        match update {
+            LegacyClientUpdate::Window(window) => ClientUpdate::Window(window),
+            LegacyClientUpdate::Magnet(magnet) => ClientUpdate::Magnet(magnet.into()),
+        }
+    }
+}
+
+/// A move in the original format, which has no `z_index` to check against.
+#[derive(Debug, Serialize, Deserialize)]
+struct LegacyClientMagnetUpdate {
+    // Only used to tell legacy updates apart from windows
+    is_magnet_update: bool,
+    id: i32,
+    x: i32,
+    y: i32,
+    rotation: i32,
+}
+
+impl From<LegacyClientMagnetUpdate> for ClientMagnetUpdate {
+    fn from(update: LegacyClientMagnetUpdate) -> Self {
+        ClientMagnetUpdate {
+            id: update.id,
+            x: update.x,
+            y: update.y,
+            rotation: update.rotation,
+            z_index: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::*;
+
+    fn decode(protocol: Protocol, request: &Value) -> ClientRequest {
+        protocol
+            .decode(&rmp_serde::to_vec(request).unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn legacy_windows_are_arrays_of_corners() {
+        let request = decode(Protocol::Legacy, &json!([-10, -20, 30, 40]));
+        assert_eq!(request.request_id, None);
+        let ClientUpdate::Window(window) = request.update else {
+            panic!("Expected a window, got {request:?}");
+        };
+        assert_eq!(
+            (window.x1, window.y1, window.x2, window.y2),
+            (-10, -20, 30, 40)
+        );
+    }
+
+    #[test]
+    fn legacy_moves_are_arrays_without_z_index() {
+        let request = decode(Protocol::Legacy, &json!([true, 7, 10, 20, -45]));
+        assert_eq!(request.request_id, None);
+        let ClientUpdate::Magnet(update) = request.update else {
+            panic!("Expected a move, got {request:?}");
+        };
+        assert_eq!(
+            (
+                update.id,
+                update.x,
+                update.y,
+                update.rotation,
+                update.z_index
+            ),
+            (7, 10, 20, -45, None)
+        );
+    }
+
+    #[test]
+    fn legacy_requests_of_other_shapes_are_rejected() {
+        let payload = rmp_serde::to_vec(&json!([1, 2, 3])).unwrap();
+        assert!(Protocol::Legacy.decode(&payload).is_err());
+        let payload = rmp_serde::to_vec(&json!({ "type": "viewers" })).unwrap();
+        assert!(Protocol::Legacy.decode(&payload).is_err());
+    }
+
+    #[test]
+    fn v2_requests_are_tagged_maps_with_an_optional_request_id() {
+        let request = decode(Protocol::V2, &json!({ "type": "viewers" }));
+        assert_eq!(request.request_id, None);
+        assert!(matches!(request.update, ClientUpdate::Viewers));
+
+        let request = decode(
+            Protocol::V2,
+            &json!({ "type": "undo", "request_id": 3, "count": 2 }),
+        );
+        assert_eq!(request.request_id, Some(3));
+        assert!(matches!(request.update, ClientUpdate::Undo { count: 2 }));
+    }
+
+    #[test]
+    fn v2_moves_can_say_which_z_index_they_expect() {
+        let request = decode(
+            Protocol::V2,
+            &json!({ "type": "magnet", "id": 7, "x": 10, "y": 20, "rotation": 0 }),
+        );
+        let ClientUpdate::Magnet(update) = request.update else {
+            panic!("Expected a move, got {request:?}");
+        };
+        assert_eq!(update.z_index, None);
+
+        let request = decode(
+            Protocol::V2,
+            &json!({
+                "type": "magnet", "request_id": 1, "id": 7, "x": 10, "y": 20, "rotation": 0,
+                "z_index": 42
+            }),
+        );
+        assert_eq!(request.request_id, Some(1));
+        let ClientUpdate::Magnet(update) = request.update else {
+            panic!("Expected a move, got {request:?}");
+        };
+        assert_eq!((update.id, update.z_index), (7, Some(42)));
+    }
+}
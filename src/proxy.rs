@@ -0,0 +1,396 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::Duration,
+};
+
+use http::HeaderMap;
+use ipnet::IpNet;
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt as _},
+    time::timeout,
+};
+
+/// How long a proxy gets to send its PROXY protocol header.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest a version 1 header can be, including the CRLF.
+const MAX_V1_HEADER: usize = 107;
+/// Shortest a version 1 header can be, `PROXY UNKNOWN\r\n`. Reading this
+/// much up front can't take anything past the header of either version.
+const MIN_V1_HEADER: usize = 15;
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Where to find a client's IP when it connects through a trusted proxy.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientIpSource {
+    /// The address of the TCP connection, for when nothing's in front
+    #[default]
+    Peer,
+    /// Cloudflare's `CF-Connecting-IP` header
+    CfConnectingIp,
+    /// The rightmost address in `X-Forwarded-For` that isn't a trusted proxy
+    XForwardedFor,
+    /// A PROXY protocol header, version 1 or 2, at the start of the
+    /// connection
+    ProxyProtocol,
+}
+
+/// Proxies whose word is taken for who the client is. Anyone else could be
+/// making it up.
+#[derive(Debug, Default)]
+pub struct TrustedProxies {
+    source: ClientIpSource,
+    networks: Vec<IpNet>,
+}
+
+impl TrustedProxies {
+    pub fn new(source: ClientIpSource, networks: Vec<IpNet>) -> TrustedProxies {
+        TrustedProxies { source, networks }
+    }
+
+    fn trusts(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.networks.iter().any(|network| network.contains(&ip))
+    }
+
+    /// Reads the PROXY protocol header a trusted proxy starts the connection
+    /// with, returning the client's IP from it. Connections from anyone else
+    /// are left alone and their own IP returned.
+    pub async fn read_proxy_header<S: AsyncRead + Unpin>(
+        &self,
+        stream: &mut S,
+        peer_ip: IpAddr,
+    ) -> io::Result<IpAddr> {
+        if self.source != ClientIpSource::ProxyProtocol || !self.trusts(peer_ip) {
+            return Ok(peer_ip);
+        }
+
+        let client_ip = timeout(HEADER_TIMEOUT, read_proxy_header(stream))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+        // Health checks and the like come from the proxy itself
+        Ok(client_ip.unwrap_or(peer_ip))
+    }
+
+    /// Picks out the client's IP from the request headers if the request was
+    /// passed on by a trusted proxy, or uses the IP it came from otherwise.
+    pub fn client_ip(&self, peer_ip: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.trusts(peer_ip) {
+            return peer_ip;
+        }
+
+        match self.source {
+            ClientIpSource::Peer | ClientIpSource::ProxyProtocol => peer_ip,
+            ClientIpSource::CfConnectingIp => headers
+                .get("CF-Connecting-IP")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse().ok())
+                .unwrap_or(peer_ip),
+            ClientIpSource::XForwardedFor => {
+                // Each proxy appends who it heard from, so the first address
+                // from the right that isn't a trusted proxy is the client
+                let forwarded_for: Vec<&str> = headers
+                    .get_all("X-Forwarded-For")
+                    .iter()
+                    .filter_map(|value| value.to_str().ok())
+                    .flat_map(|value| value.split(','))
+                    .collect();
+
+                let mut client_ip = peer_ip;
+                for entry in forwarded_for.into_iter().rev() {
+                    let Ok(ip) = entry.trim().parse::<IpAddr>() else {
+                        break;
+                    };
+                    client_ip = ip.to_canonical();
+                    if !self.trusts(client_ip) {
+                        break;
+                    }
+                }
+                client_ip
+            }
+        }
+    }
+}
+
+/// Parses a trusted proxy, either a network like `10.0.0.0/8` or a single
+/// address.
+pub fn parse_network(network: &str) -> Result<IpNet, String> {
+    let network = network.trim();
+    network
+        .parse::<IpNet>()
+        .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("Invalid trusted proxy {network:?}: expected an address or CIDR"))
+}
+
+/// Reads a version 1 or 2 header without reading past it. Returns `None` if
+/// the proxy isn't passing on a client, like for its own health checks.
+async fn read_proxy_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<IpAddr>> {
+    let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidData, reason.to_string());
+
+    let mut header = vec![0; MIN_V1_HEADER];
+    stream.read_exact(&mut header).await?;
+
+    if header.starts_with(b"PROXY ") {
+        while !header.ends_with(b"\r\n") {
+            if header.len() == MAX_V1_HEADER {
+                return Err(invalid("PROXY header too long"));
+            }
+            header.push(stream.read_u8().await?);
+        }
+
+        let header = std::str::from_utf8(&header[..header.len() - 2])
+            .map_err(|_| invalid("PROXY header isn't text"))?;
+        let mut fields = header.split(' ').skip(1);
+        return match fields.next() {
+            Some("TCP4" | "TCP6") => fields
+                .next()
+                .and_then(|source| source.parse().ok())
+                .map(|ip: IpAddr| Some(ip.to_canonical()))
+                .ok_or_else(|| invalid("PROXY header has no source address")),
+            Some("UNKNOWN") => Ok(None),
+            _ => Err(invalid("PROXY header has an unknown protocol")),
+        };
+    }
+
+    if !header.starts_with(V2_SIGNATURE) {
+        return Err(invalid("Connection didn't start with a PROXY header"));
+    }
+    header.push(stream.read_u8().await?);
+    let (version_command, family) = (header[12], header[13]);
+    let length = u16::from_be_bytes([header[14], header[15]]);
+
+    let mut addresses = vec![0; usize::from(length)];
+    stream.read_exact(&mut addresses).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("PROXY header has an unknown version"));
+    }
+    if version_command & 0x0f == 0 {
+        // LOCAL, the proxy talking for itself
+        return Ok(None);
+    }
+    match family >> 4 {
+        1 if addresses.len() >= 12 => {
+            let source: [u8; 4] = addresses[..4].try_into().unwrap();
+            Ok(Some(IpAddr::V4(Ipv4Addr::from(source))))
+        }
+        2 if addresses.len() >= 36 => {
+            let source: [u8; 16] = addresses[..16].try_into().unwrap();
+            Ok(Some(IpAddr::V6(Ipv6Addr::from(source)).to_canonical()))
+        }
+        1 | 2 => Err(invalid("PROXY header addresses are truncated")),
+        // Unix sockets and unspecified families don't have an IP
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn proxies(source: ClientIpSource, networks: &[&str]) -> TrustedProxies {
+        let networks = networks
+            .iter()
+            .map(|network| parse_network(network).unwrap())
+            .collect();
+        TrustedProxies::new(source, networks)
+    }
+
+    fn headers(name: &'static str, values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn v2_header(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&u16::try_from(addresses.len()).unwrap().to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    /// Reads a header from the start of `bytes`, and returns what it left
+    /// unread.
+    async fn read(bytes: &[u8]) -> (io::Result<Option<IpAddr>>, &[u8]) {
+        let mut stream = bytes;
+        let result = read_proxy_header(&mut stream).await;
+        (result, stream)
+    }
+
+    #[tokio::test]
+    async fn reads_v1_headers_and_nothing_after() {
+        let (ip, rest) =
+            read(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 8080\r\nGET / HTTP/1.1").await;
+        assert_eq!(ip.unwrap(), Some(self::ip("203.0.113.7")));
+        assert_eq!(rest, b"GET / HTTP/1.1");
+
+        let (ip, rest) = read(b"PROXY TCP6 2001:db8::7 2001:db8::1 51234 8080\r\nGET").await;
+        assert_eq!(ip.unwrap(), Some(self::ip("2001:db8::7")));
+        assert_eq!(rest, b"GET");
+
+        let (ip, _) = read(b"PROXY TCP6 ::ffff:203.0.113.7 ::1 51234 8080\r\nGET").await;
+        assert_eq!(ip.unwrap(), Some(self::ip("203.0.113.7")));
+
+        let (ip, rest) = read(b"PROXY UNKNOWN\r\nGET").await;
+        assert_eq!(ip.unwrap(), None);
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn reads_v2_headers_and_nothing_after() {
+        let mut addresses = vec![203, 0, 113, 7, 10, 0, 0, 1];
+        addresses.extend_from_slice(&51234_u16.to_be_bytes());
+        addresses.extend_from_slice(&8080_u16.to_be_bytes());
+        let mut bytes = v2_header(1, 0x11, &addresses);
+        bytes.extend_from_slice(b"GET");
+        let (ip, rest) = read(&bytes).await;
+        assert_eq!(ip.unwrap(), Some(self::ip("203.0.113.7")));
+        assert_eq!(rest, b"GET");
+
+        let source: Ipv6Addr = "2001:db8::7".parse().unwrap();
+        let mut addresses = source.octets().to_vec();
+        addresses.extend_from_slice(&[0; 20]);
+        let (ip, _) = read(&v2_header(1, 0x21, &addresses)).await;
+        assert_eq!(ip.unwrap(), Some(IpAddr::V6(source)));
+
+        // IPv4 clients of an IPv6 proxy are the same clients
+        let mapped: Ipv6Addr = "::ffff:203.0.113.7".parse().unwrap();
+        let mut addresses = mapped.octets().to_vec();
+        addresses.extend_from_slice(&[0; 20]);
+        let (ip, _) = read(&v2_header(1, 0x21, &addresses)).await;
+        assert_eq!(ip.unwrap(), Some(self::ip("203.0.113.7")));
+    }
+
+    #[tokio::test]
+    async fn v2_local_and_unspecified_headers_have_no_client() {
+        let mut bytes = v2_header(0, 0x11, &[0; 12]);
+        bytes.extend_from_slice(b"GET");
+        let (ip, rest) = read(&bytes).await;
+        assert_eq!(ip.unwrap(), None);
+        assert_eq!(rest, b"GET");
+
+        let (ip, _) = read(&v2_header(1, 0x00, &[])).await;
+        assert_eq!(ip.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_anything_but_a_header() {
+        for bytes in [
+            &b"GET / HTTP/1.1\r\nHost: fridgepoem.com\r\n\r\n"[..],
+            b"PROXY TCP4 not-an-ip 10.0.0.1 1 2\r\n",
+            b"PROXY UDP4 203.0.113.7 10.0.0.1 1 2\r\n",
+            b"PROXY TCP4\r\n\r\n\r\n\r\n\r\n",
+            b"PROXY TCP4 203.0.113.7",
+        ] {
+            let (ip, _) = read(bytes).await;
+            assert!(
+                ip.is_err(),
+                "{:?} shouldn't be read",
+                String::from_utf8_lossy(bytes)
+            );
+        }
+
+        let mut too_long = b"PROXY TCP4 ".to_vec();
+        too_long.resize(200, b'1');
+        assert!(read(&too_long).await.0.is_err());
+
+        // Truncated addresses, and a version other than 2
+        assert!(read(&v2_header(1, 0x11, &[203, 0, 113])).await.0.is_err());
+        let mut bytes = v2_header(1, 0x11, &[0; 12]);
+        bytes[12] = 0x31;
+        assert!(read(&bytes).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn only_trusted_proxies_are_asked_for_a_header() {
+        let proxies = proxies(ClientIpSource::ProxyProtocol, &["10.0.0.0/8"]);
+
+        let mut stream = &b"GET / HTTP/1.1\r\n"[..];
+        let client = proxies
+            .read_proxy_header(&mut stream, ip("198.51.100.1"))
+            .await;
+        assert_eq!(client.unwrap(), ip("198.51.100.1"));
+        assert_eq!(stream, b"GET / HTTP/1.1\r\n");
+
+        let mut stream = &b"PROXY TCP4 203.0.113.7 10.0.0.1 1 2\r\n"[..];
+        let client = proxies.read_proxy_header(&mut stream, ip("10.1.2.3")).await;
+        assert_eq!(client.unwrap(), ip("203.0.113.7"));
+
+        let mut stream = &b"PROXY UNKNOWN\r\n"[..];
+        let client = proxies.read_proxy_header(&mut stream, ip("10.1.2.3")).await;
+        assert_eq!(client.unwrap(), ip("10.1.2.3"));
+    }
+
+    #[test]
+    fn takes_cf_connecting_ip_only_from_trusted_proxies() {
+        let proxies = proxies(ClientIpSource::CfConnectingIp, &["172.16.0.0/12"]);
+        let cf = headers("CF-Connecting-IP", &["203.0.113.7"]);
+
+        assert_eq!(proxies.client_ip(ip("172.19.0.2"), &cf), ip("203.0.113.7"));
+        assert_eq!(
+            proxies.client_ip(ip("198.51.100.1"), &cf),
+            ip("198.51.100.1")
+        );
+        assert_eq!(
+            proxies.client_ip(ip("172.19.0.2"), &HeaderMap::new()),
+            ip("172.19.0.2")
+        );
+        assert_eq!(
+            proxies.client_ip(ip("172.19.0.2"), &headers("CF-Connecting-IP", &["garbage"])),
+            ip("172.19.0.2")
+        );
+    }
+
+    #[test]
+    fn takes_the_rightmost_untrusted_x_forwarded_for() {
+        let proxies = proxies(ClientIpSource::XForwardedFor, &["10.0.0.0/8"]);
+        let client_ip = |values: &[&str]| {
+            proxies.client_ip(ip("10.0.0.1"), &headers("X-Forwarded-For", values))
+        };
+
+        // Whatever the client put first could be made up
+        assert_eq!(client_ip(&["1.2.3.4, 203.0.113.7"]), ip("203.0.113.7"));
+        assert_eq!(
+            client_ip(&["1.2.3.4, 203.0.113.7, 10.0.0.5"]),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            client_ip(&["1.2.3.4", "203.0.113.7, 10.0.0.5"]),
+            ip("203.0.113.7")
+        );
+        // Going no further left than something that isn't an address
+        assert_eq!(
+            client_ip(&["203.0.113.7, garbage, 10.0.0.5"]),
+            ip("10.0.0.5")
+        );
+        // A chain of nothing but proxies ends at the leftmost one
+        assert_eq!(client_ip(&["10.0.0.9, 10.0.0.5"]), ip("10.0.0.9"));
+        assert_eq!(client_ip(&[]), ip("10.0.0.1"));
+        assert_eq!(client_ip(&["::ffff:203.0.113.7"]), ip("203.0.113.7"));
+
+        let untrusted = headers("X-Forwarded-For", &["203.0.113.7"]);
+        assert_eq!(
+            proxies.client_ip(ip("198.51.100.1"), &untrusted),
+            ip("198.51.100.1")
+        );
+    }
+
+    #[test]
+    fn peer_source_ignores_headers() {
+        let proxies = proxies(ClientIpSource::Peer, &["10.0.0.0/8"]);
+        let cf = headers("CF-Connecting-IP", &["203.0.113.7"]);
+        assert_eq!(proxies.client_ip(ip("10.0.0.1"), &cf), ip("10.0.0.1"));
+    }
+}
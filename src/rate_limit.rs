@@ -0,0 +1,229 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How often requests of one kind can be made, as a token bucket refilling at
+/// `per_second` and holding at most `burst` tokens.
+#[derive(Clone, Copy, Debug)]
+pub struct Budget {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+impl Budget {
+    fn scaled(self, factor: f64) -> Budget {
+        Budget {
+            per_second: self.per_second * factor,
+            burst: self.burst * factor,
+        }
+    }
+}
+
+/// Rejected requests a session can rack up before it's considered abusive.
+/// Clients that wait as long as they're told to shouldn't come close.
+const REJECTIONS: Budget = Budget {
+    per_second: 1.0,
+    burst: 20.0,
+};
+
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimits {
+    pub window_updates: Budget,
+    pub moves: Budget,
+    /// How many sessions' worth of requests can come from one peer, however
+    /// many sessions it opens
+    pub sessions_per_peer: f64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum RequestKind {
+    /// Anything that only reads, like window updates and history requests
+    WindowUpdate,
+    /// Anything that moves magnets
+    Move,
+}
+
+impl RateLimits {
+    fn budget(&self, kind: RequestKind) -> Budget {
+        match kind {
+            RequestKind::WindowUpdate => self.window_updates,
+            RequestKind::Move => self.moves,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    fn full(budget: Budget) -> TokenBucket {
+        TokenBucket {
+            tokens: budget.burst,
+            updated_at: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, budget: Budget, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * budget.per_second).min(budget.burst);
+        self.updated_at = now;
+    }
+
+    /// Returns how long until a token is available if there isn't one now.
+    fn check(&mut self, budget: Budget, now: Instant) -> Result<(), Duration> {
+        self.refill(budget, now);
+        if self.tokens >= 1.0 {
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / budget.per_second,
+            ))
+        }
+    }
+
+    fn is_full(&mut self, budget: Budget, now: Instant) -> bool {
+        self.refill(budget, now);
+        self.tokens >= budget.burst
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    window_updates: TokenBucket,
+    moves: TokenBucket,
+}
+
+impl Buckets {
+    fn full(limits: RateLimits) -> Buckets {
+        Buckets {
+            window_updates: TokenBucket::full(limits.window_updates),
+            moves: TokenBucket::full(limits.moves),
+        }
+    }
+
+    fn get_mut(&mut self, kind: RequestKind) -> &mut TokenBucket {
+        match kind {
+            RequestKind::WindowUpdate => &mut self.window_updates,
+            RequestKind::Move => &mut self.moves,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Peer {
+    sessions: usize,
+    buckets: Buckets,
+}
+
+/// Request budgets for every connected peer, shared by all of its sessions.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    peers: Mutex<HashMap<IpAddr, Peer>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> RateLimiter {
+        RateLimiter {
+            limits,
+            peers: Default::default(),
+        }
+    }
+
+    fn peer_limits(&self) -> RateLimits {
+        RateLimits {
+            window_updates: self
+                .limits
+                .window_updates
+                .scaled(self.limits.sessions_per_peer),
+            moves: self.limits.moves.scaled(self.limits.sessions_per_peer),
+            ..self.limits
+        }
+    }
+
+    pub fn session(self: &Arc<Self>, peer: IpAddr) -> SessionRateLimiter {
+        let peer_limits = self.peer_limits();
+        let now = Instant::now();
+
+        let mut peers = self.peers.lock().unwrap();
+        // Forget peers that have left once they've earned back their budget,
+        // so that reconnecting doesn't reset it
+        peers.retain(|_, peer| {
+            peer.sessions > 0
+                || !peer
+                    .buckets
+                    .window_updates
+                    .is_full(peer_limits.window_updates, now)
+                || !peer.buckets.moves.is_full(peer_limits.moves, now)
+        });
+        peers
+            .entry(peer)
+            .or_insert_with(|| Peer {
+                sessions: 0,
+                buckets: Buckets::full(peer_limits),
+            })
+            .sessions += 1;
+
+        SessionRateLimiter {
+            limiter: self.clone(),
+            peer,
+            buckets: Buckets::full(self.limits),
+            rejections: TokenBucket::full(REJECTIONS),
+        }
+    }
+}
+
+/// One session's budget, drawing on its peer's as well.
+#[derive(Debug)]
+pub struct SessionRateLimiter {
+    limiter: Arc<RateLimiter>,
+    peer: IpAddr,
+    buckets: Buckets,
+    rejections: TokenBucket,
+}
+
+impl SessionRateLimiter {
+    /// Uses up a request of `kind`, or returns how long to wait before trying
+    /// again if either the session or its peer is out of budget.
+    pub fn check(&mut self, kind: RequestKind) -> Result<(), Duration> {
+        let now = Instant::now();
+        let budget = self.limiter.limits.budget(kind);
+        let peer_budget = self.limiter.peer_limits().budget(kind);
+
+        let session_bucket = self.buckets.get_mut(kind);
+        session_bucket.check(budget, now)?;
+
+        let mut peers = self.limiter.peers.lock().unwrap();
+        let peer_bucket = peers.get_mut(&self.peer).unwrap().buckets.get_mut(kind);
+        peer_bucket.check(peer_budget, now)?;
+
+        session_bucket.tokens -= 1.0;
+        peer_bucket.tokens -= 1.0;
+        Ok(())
+    }
+
+    /// Counts a request rejected by `check`. Returns whether the session has
+    /// been over its limits for long enough to be cut off.
+    pub fn record_rejection(&mut self) -> bool {
+        if self.rejections.check(REJECTIONS, Instant::now()).is_err() {
+            return true;
+        }
+        self.rejections.tokens -= 1.0;
+        false
+    }
+}
+
+impl Drop for SessionRateLimiter {
+    fn drop(&mut self) {
+        let mut peers = self.limiter.peers.lock().unwrap();
+        if let Some(peer) = peers.get_mut(&self.peer) {
+            peer.sessions -= 1;
+        }
+    }
+}
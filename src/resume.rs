@@ -0,0 +1,227 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use subtle::ConstantTimeEq as _;
+use tokio::{select, sync::broadcast, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::{
+    board::Board,
+    geometry::Window,
+    state::{ChangeEvent, PgMagnetUpdate},
+    storage::MoveRecord,
+    tiles::Subscription,
+};
+
+/// How long a disconnected session can be picked back up by its client.
+const RESUME_WINDOW: Duration = Duration::from_secs(30);
+/// Past this many missed updates the client gets a full refresh instead.
+const MAX_MISSED_UPDATES: usize = 256;
+
+/// A client's request, made through the query string of the upgrade request
+/// (`?session_id=...&resume_token=...`), to pick up a previous session.
+#[derive(Debug)]
+pub struct ResumeRequest {
+    pub session_id: Uuid,
+    pub resume_token: String,
+}
+
+impl ResumeRequest {
+    pub fn from_query(query: Option<&str>) -> Option<ResumeRequest> {
+        let mut session_id = None;
+        let mut resume_token = None;
+
+        for pair in query?.split('&') {
+            match pair.split_once('=') {
+                Some(("session_id", value)) => session_id = Uuid::parse_str(value).ok(),
+                Some(("resume_token", value)) => resume_token = Some(value.to_string()),
+                _ => {}
+            }
+        }
+
+        Some(ResumeRequest {
+            session_id: session_id?,
+            resume_token: resume_token?,
+        })
+    }
+}
+
+pub fn new_resume_token() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+/// Everything needed to continue a session on a new connection.
+#[derive(Debug)]
+pub struct ResumedSession {
+    pub board: Arc<Board>,
+    pub rx: Subscription,
+    pub client_window: Window,
+    pub undo_stack: VecDeque<MoveRecord>,
+    /// Updates relevant to `client_window` that arrived while disconnected
+    pub missed_updates: Vec<PgMagnetUpdate>,
+    /// Set when `missed_updates` is incomplete and the window must be
+    /// refreshed from scratch
+    pub lagged: bool,
+}
+
+impl ResumedSession {
+    pub fn new(
+        board: Arc<Board>,
+        rx: Subscription,
+        client_window: Window,
+        undo_stack: VecDeque<MoveRecord>,
+    ) -> ResumedSession {
+        ResumedSession {
+            board,
+            rx,
+            client_window,
+            undo_stack,
+            missed_updates: Vec::new(),
+            lagged: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SuspendedSession {
+    board_id: String,
+    resume_token: String,
+    resume: CancellationToken,
+    handle: JoinHandle<ResumedSession>,
+}
+
+/// Sessions whose connection dropped, kept around for `RESUME_WINDOW` in case
+/// the client reconnects.
+#[derive(Clone, Debug, Default)]
+pub struct SuspendedSessions(Arc<Mutex<HashMap<Uuid, SuspendedSession>>>);
+
+impl SuspendedSessions {
+    /// Keeps consuming `rx` on behalf of a disconnected session, so that it
+    /// doesn't fall behind, remembering the updates that
+    /// concern its window.
+    #[tracing::instrument(skip(self, resume_token, session, shutdown))]
+    pub fn suspend(
+        &self,
+        session_id: Uuid,
+        resume_token: String,
+        session: ResumedSession,
+        shutdown: CancellationToken,
+    ) {
+        tracing::debug!("Suspending session");
+        session.rx.set_suspended(true);
+
+        let board_id = session.board.id.clone();
+        let resume = CancellationToken::new();
+        let handle = tokio::spawn(buffer_missed_updates(
+            self.clone(),
+            session_id,
+            session,
+            resume.clone(),
+            shutdown,
+        ));
+
+        self.0.lock().unwrap().insert(
+            session_id,
+            SuspendedSession {
+                board_id,
+                resume_token,
+                resume,
+                handle,
+            },
+        );
+    }
+
+    /// Hands a suspended session back if the token matches and the client
+    /// reconnected to the same board. Otherwise the session is left suspended,
+    /// so that a bad request can't throw it away.
+    #[tracing::instrument(skip(self, request), fields(session_id = %request.session_id))]
+    pub async fn resume(&self, board_id: &str, request: &ResumeRequest) -> Option<ResumedSession> {
+        let suspended = {
+            let mut sessions = self.0.lock().unwrap();
+            match sessions.get(&request.session_id) {
+                Some(s)
+                    if !bool::from(
+                        s.resume_token
+                            .as_bytes()
+                            .ct_eq(request.resume_token.as_bytes()),
+                    ) =>
+                {
+                    tracing::debug!("Rejecting resume request with invalid token");
+                    return None;
+                }
+                // Sessions can't move between boards
+                Some(s) if s.board_id != board_id => {
+                    tracing::debug!("Rejecting resume request for board {board_id}");
+                    return None;
+                }
+                Some(_) => sessions.remove(&request.session_id)?,
+                None => {
+                    tracing::debug!("No suspended session to resume");
+                    return None;
+                }
+            }
+        };
+
+        suspended.resume.cancel();
+        let session = suspended.handle.await.ok()?;
+        session.rx.set_suspended(false);
+        Some(session)
+    }
+}
+
+async fn buffer_missed_updates(
+    sessions: SuspendedSessions,
+    session_id: Uuid,
+    mut session: ResumedSession,
+    resume: CancellationToken,
+    shutdown: CancellationToken,
+) -> ResumedSession {
+    let expiry = tokio::time::sleep(RESUME_WINDOW);
+    tokio::pin!(expiry);
+
+    loop {
+        select! {
+            () = resume.cancelled() => {
+                return session;
+            }
+            () = shutdown.cancelled() => {
+                break;
+            }
+            () = &mut expiry => {
+                tracing::debug!("Suspended session {session_id} expired");
+                break;
+            }
+            // Holds are short-lived, and the client will see magnets move
+            // whether it knows they're held or not
+            change_event = session.rx.recv_change() => match change_event {
+                Ok(ChangeEvent::Update(magnet_update)) => {
+                    let window = &session.client_window;
+                    if !window.contains(magnet_update.old_x, magnet_update.old_y)
+                        && !window.contains(magnet_update.new_x, magnet_update.new_y)
+                    {
+                        continue;
+                    }
+
+                    if session.missed_updates.len() < MAX_MISSED_UPDATES {
+                        session.missed_updates.push(magnet_update);
+                    } else {
+                        session.lagged = true;
+                    }
+                }
+                Ok(ChangeEvent::Gap) | Err(broadcast::error::RecvError::Lagged(_)) => {
+                    session.lagged = true;
+                }
+                Err(broadcast::error::RecvError::Closed) => {
+                    break;
+                }
+            }
+        }
+    }
+
+    sessions.0.lock().unwrap().remove(&session_id);
+    session
+}
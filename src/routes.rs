@@ -0,0 +1,133 @@
+use http::{Method, Request, StatusCode};
+use tokio::io::AsyncWrite;
+
+use crate::{board::Board, gallery, handshake, state::AppState};
+
+/// What a request is for, going by its path.
+#[derive(Debug)]
+pub enum Route<'a> {
+    /// Whether the server is up at all
+    Health,
+    /// Whether the server can take sessions, which it can't without storage
+    /// or changes to pass on
+    Ready,
+    SavedPoem(&'a str),
+    /// A WebSocket connection to a board
+    Board(&'a str),
+    NotFound,
+}
+
+impl Route<'_> {
+    pub fn of(path: &str) -> Route<'_> {
+        match path {
+            "/health" => Route::Health,
+            "/ready" => Route::Ready,
+            path => {
+                if let Some(slug) = path.strip_prefix("/poems/") {
+                    Route::SavedPoem(slug)
+                } else if let Some(board_id) = Board::id_from_path(path) {
+                    Route::Board(board_id)
+                } else {
+                    Route::NotFound
+                }
+            }
+        }
+    }
+}
+
+/// Answers a plain HTTP request, anything but a WebSocket upgrade.
+#[tracing::instrument(skip(stream, request, state))]
+pub async fn serve<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    request: &Request<()>,
+    route: Route<'_>,
+    state: &AppState,
+) -> std::io::Result<()> {
+    let is_get = request.method() == Method::GET;
+    match route {
+        Route::Health if is_get => respond_text(stream, StatusCode::OK, "ok").await,
+        Route::Ready if is_get => serve_ready(stream, state).await,
+        Route::SavedPoem(slug) if is_get => {
+            gallery::serve_saved_poem(stream, slug, request, state).await
+        }
+        Route::Health | Route::Ready | Route::SavedPoem(_) => {
+            handshake::reject(stream, StatusCode::METHOD_NOT_ALLOWED).await
+        }
+        Route::Board(_) => {
+            tracing::debug!("Rejecting plain request for a WebSocket endpoint");
+            handshake::reject(stream, StatusCode::BAD_REQUEST).await
+        }
+        Route::NotFound => {
+            tracing::debug!(
+                "Rejecting request for unknown path {}",
+                request.uri().path()
+            );
+            handshake::reject(stream, StatusCode::NOT_FOUND).await
+        }
+    }
+}
+
+/// Answers a request to the metrics address, where there's nothing but
+/// counters for Prometheus to scrape.
+#[tracing::instrument(skip(stream, request, state))]
+pub async fn serve_metrics<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    request: &Request<()>,
+    state: &AppState,
+) -> std::io::Result<()> {
+    if request.uri().path() != "/metrics" {
+        return handshake::reject(stream, StatusCode::NOT_FOUND).await;
+    }
+    if request.method() != Method::GET {
+        return handshake::reject(stream, StatusCode::METHOD_NOT_ALLOWED).await;
+    }
+
+    handshake::respond(
+        stream,
+        StatusCode::OK,
+        &[("Content-Type", "text/plain; version=0.0.4; charset=utf-8")],
+        state.metrics.render(&state.boards).as_bytes(),
+    )
+    .await
+}
+
+async fn serve_ready<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    state: &AppState,
+) -> std::io::Result<()> {
+    if state.broadcast_changes.is_finished() {
+        tracing::warn!("Not ready, changes are no longer being passed on");
+        return respond_text(
+            stream,
+            StatusCode::SERVICE_UNAVAILABLE,
+            "not passing on changes",
+        )
+        .await;
+    }
+
+    if let Err(e) = state.storage.ping().await {
+        tracing::warn!("Not ready, storage is unavailable: {e}");
+        return respond_text(
+            stream,
+            StatusCode::SERVICE_UNAVAILABLE,
+            "storage unavailable",
+        )
+        .await;
+    }
+
+    respond_text(stream, StatusCode::OK, "ready").await
+}
+
+async fn respond_text<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    status: StatusCode,
+    text: &str,
+) -> std::io::Result<()> {
+    handshake::respond(
+        stream,
+        status,
+        &[("Content-Type", "text/plain; charset=utf-8")],
+        text.as_bytes(),
+    )
+    .await
+}
@@ -1,8 +1,18 @@
+use std::{sync::Arc, time::Duration};
+
 use serde::{Deserialize, Serialize};
+use tokio::task::AbortHandle;
+
+use crate::{
+    board::Boards, cache::MagnetCache, metrics::Metrics, origins::AllowedOrigins,
+    proxy::TrustedProxies, rate_limit::RateLimiter, resume::SuspendedSessions, storage::Storage,
+    tls::Tls, websocket::SessionTimeouts,
+};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PgMagnetUpdate {
     pub id: i32,
+    pub board_id: String,
     pub old_x: i32,
     pub old_y: i32,
     pub new_x: i32,
@@ -12,9 +22,32 @@ pub struct PgMagnetUpdate {
     pub word: String,
 }
 
+/// What storage reports about changes to magnets.
+#[derive(Clone, Debug)]
+pub enum ChangeEvent {
+    Update(PgMagnetUpdate),
+    /// The change stream was interrupted and updates may have been lost, so
+    /// every session has to refresh its window from scratch.
+    Gap,
+}
+
 #[derive(Clone, Debug)]
 pub struct AppState {
-    pub postgres: sqlx::PgPool,
-    pub magnet_updates: tokio::sync::broadcast::Sender<PgMagnetUpdate>,
+    pub storage: Arc<dyn Storage>,
+    pub boards: Boards,
+    pub magnet_cache: Option<Arc<MagnetCache>>,
     pub token: tokio_util::sync::CancellationToken,
+    pub suspended_sessions: SuspendedSessions,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub metrics: Arc<Metrics>,
+    pub allowed_origins: Arc<AllowedOrigins>,
+    pub trusted_proxies: Arc<TrustedProxies>,
+    /// The task passing on changes from storage, which sessions can't do
+    /// without
+    pub broadcast_changes: AbortHandle,
+    pub session_timeouts: SessionTimeouts,
+    /// How far back sessions can look at history
+    pub history_retention: Duration,
+    /// Set when terminating TLS ourselves
+    pub tls: Option<Arc<Tls>>,
 }
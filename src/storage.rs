@@ -0,0 +1,98 @@
+mod memory;
+mod postgres;
+
+use std::fmt::Debug;
+
+use async_trait::async_trait;
+use futures_util::stream::BoxStream;
+use sqlx::types::chrono::{DateTime, Utc};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+pub use self::{memory::MemoryStorage, postgres::PgStorage};
+use crate::{
+    gallery::SavedPoem,
+    geometry::{Shape, Window},
+    protocol::{ClientMagnetUpdate, Magnet},
+    state::ChangeEvent,
+};
+
+/// A move made by a session, with what's needed to take it back.
+#[derive(Clone, Debug)]
+pub struct MoveRecord {
+    pub id: i32,
+    pub old_x: i32,
+    pub old_y: i32,
+    pub old_rotation: i32,
+    /// The magnet's `z_index` right after the move. If it has changed since,
+    /// someone else has moved the magnet in the meantime.
+    pub z_index: i64,
+}
+
+/// Where boards and magnets are kept, and where changes to them come from.
+///
+/// Errors are reported as `sqlx::Error` whatever the backend, so that they
+/// map onto `FridgeError` the same way.
+#[async_trait]
+pub trait Storage: Debug + Send + Sync {
+    async fn board_bounds(&self, board_id: &str) -> Result<Option<Window>, sqlx::Error>;
+
+    async fn magnets_in(&self, board_id: &str, shape: &Shape) -> Result<Vec<Magnet>, sqlx::Error>;
+
+    /// Where a magnet is now, or `None` if it isn't on the board.
+    async fn magnet(&self, board_id: &str, id: i32) -> Result<Option<Magnet>, sqlx::Error>;
+
+    /// The magnets that were in `window` at `at`, where they were then.
+    async fn magnets_at(
+        &self,
+        board_id: &str,
+        window: &Window,
+        at: DateTime<Utc>,
+    ) -> Result<Vec<Magnet>, sqlx::Error>;
+
+    /// Forgets moves made before `before`, so that `magnets_at` can no longer
+    /// go back that far. Returns how many moves were forgotten.
+    async fn prune_history(&self, before: DateTime<Utc>) -> Result<u64, sqlx::Error>;
+
+    /// Every magnet on every board, along with the board it's on.
+    fn all_magnets(&self) -> BoxStream<'_, Result<(String, Magnet), sqlx::Error>>;
+
+    /// Returns `None` without moving the magnet if the update expects a
+    /// `z_index` it no longer has. Fails with `RowNotFound` if the magnet
+    /// isn't on the board.
+    async fn move_magnet(
+        &self,
+        board_id: &str,
+        update: &ClientMagnetUpdate,
+        session_id: &Uuid,
+    ) -> Result<Option<MoveRecord>, sqlx::Error>;
+
+    /// Puts a magnet back to where a session's move took it from, unless
+    /// someone else has moved it since. Returns its new `z_index` if it was
+    /// moved back.
+    async fn revert_move(
+        &self,
+        revert: &MoveRecord,
+        session_id: &Uuid,
+    ) -> Result<Option<i64>, sqlx::Error>;
+
+    /// Fails if the slug is already taken.
+    async fn save_poem(&self, poem: &SavedPoem, session_id: &Uuid) -> Result<(), sqlx::Error>;
+
+    async fn saved_poem(&self, slug: &str) -> Result<Option<SavedPoem>, sqlx::Error>;
+
+    /// Starts listening for changes to magnets on every board.
+    async fn changes(&self, token: CancellationToken) -> Result<Box<dyn ChangeFeed>, sqlx::Error>;
+
+    /// Checks that storage can be reached.
+    async fn ping(&self) -> Result<(), sqlx::Error>;
+
+    async fn close(&self);
+}
+
+#[async_trait]
+pub trait ChangeFeed: Send {
+    /// Waits for the next change, or returns `None` once storage is shutting
+    /// down. A `ChangeEvent::Gap` means changes may have been lost.
+    async fn next(&mut self) -> Option<ChangeEvent>;
+}
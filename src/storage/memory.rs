@@ -0,0 +1,380 @@
+use std::{collections::HashMap, path::Path, sync::Mutex};
+
+use async_trait::async_trait;
+use futures_util::{StreamExt as _, stream::BoxStream};
+use sqlx::types::chrono::{DateTime, Utc};
+use tokio::{select, sync::broadcast};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use super::{ChangeFeed, MoveRecord, Storage};
+use crate::{
+    board::DEFAULT_BOARD,
+    gallery::SavedPoem,
+    geometry::{Shape, Window},
+    protocol::{ClientMagnetUpdate, Magnet},
+    state::{ChangeEvent, PgMagnetUpdate},
+};
+
+#[derive(Debug)]
+struct StoredMagnet {
+    board_id: String,
+    magnet: Magnet,
+    last_modifier: Option<Uuid>,
+}
+
+/// Where a magnet was before a move, like a row of `magnet_moves`.
+#[derive(Debug)]
+struct PastMove {
+    magnet_id: i32,
+    moved_at: DateTime<Utc>,
+    old_x: i32,
+    old_y: i32,
+    old_rotation: i32,
+    old_z_index: i64,
+}
+
+#[derive(Debug)]
+struct Contents {
+    boards: HashMap<String, Window>,
+    magnets: HashMap<i32, StoredMagnet>,
+    moves: Vec<PastMove>,
+    poems: HashMap<String, SavedPoem>,
+    next_z_index: i64,
+}
+
+impl Contents {
+    fn set_position(
+        &mut self,
+        id: i32,
+        x: i32,
+        y: i32,
+        rotation: i32,
+        session_id: &Uuid,
+    ) -> (MoveRecord, PgMagnetUpdate) {
+        self.next_z_index += 1;
+        let z_index = self.next_z_index;
+
+        let stored = self.magnets.get_mut(&id).unwrap();
+        let magnet = &mut stored.magnet;
+        self.moves.push(PastMove {
+            magnet_id: id,
+            moved_at: Utc::now(),
+            old_x: magnet.x,
+            old_y: magnet.y,
+            old_rotation: magnet.rotation,
+            old_z_index: magnet.z_index,
+        });
+
+        let move_record = MoveRecord {
+            id,
+            old_x: magnet.x,
+            old_y: magnet.y,
+            old_rotation: magnet.rotation,
+            z_index,
+        };
+        let magnet_update = PgMagnetUpdate {
+            id,
+            board_id: stored.board_id.clone(),
+            old_x: magnet.x,
+            old_y: magnet.y,
+            new_x: x,
+            new_y: y,
+            rotation,
+            z_index,
+            word: magnet.word.clone(),
+        };
+
+        magnet.x = x;
+        magnet.y = y;
+        magnet.rotation = rotation;
+        magnet.z_index = z_index;
+        stored.last_modifier = Some(*session_id);
+
+        (move_record, magnet_update)
+    }
+}
+
+/// Keeps everything in process, for running without Postgres. Changes are
+/// fed back the same way Postgres notifies them. Nothing is persisted, and
+/// move history grows for as long as the server runs.
+#[derive(Debug)]
+pub struct MemoryStorage {
+    contents: Mutex<Contents>,
+    changes: broadcast::Sender<PgMagnetUpdate>,
+}
+
+impl MemoryStorage {
+    /// Starts out with an empty default board, the same size as the one
+    /// created by the migrations.
+    pub fn new(change_capacity: usize) -> MemoryStorage {
+        let default_bounds = Window {
+            x1: -500_000,
+            y1: -500_000,
+            x2: 500_000,
+            y2: 500_000,
+        };
+
+        MemoryStorage {
+            contents: Mutex::new(Contents {
+                boards: HashMap::from([(DEFAULT_BOARD.to_string(), default_bounds)]),
+                magnets: HashMap::new(),
+                moves: Vec::new(),
+                poems: HashMap::new(),
+                next_z_index: 0,
+            }),
+            changes: broadcast::Sender::new(change_capacity),
+        }
+    }
+
+    /// Places a new magnet on a board, returning its id.
+    pub fn add_magnet(&self, board_id: &str, x: i32, y: i32, rotation: i32, word: String) -> i32 {
+        let mut contents = self.contents.lock().unwrap();
+        let id = contents.magnets.len() as i32 + 1;
+        contents.next_z_index += 1;
+        let z_index = contents.next_z_index;
+
+        contents.magnets.insert(
+            id,
+            StoredMagnet {
+                board_id: board_id.to_string(),
+                magnet: Magnet {
+                    id,
+                    x,
+                    y,
+                    rotation,
+                    z_index,
+                    word,
+                },
+                last_modifier: None,
+            },
+        );
+        id
+    }
+
+    /// Adds the magnets in a CSV of `"(x,y)",rotation,word` rows to the
+    /// default board. Returns how many were added.
+    pub fn load_seed_file(&self, path: &Path) -> anyhow::Result<usize> {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_path(path)?;
+
+        let mut count = 0;
+        for record in reader.records() {
+            let record = record?;
+            let (Some(coords), Some(rotation), Some(word)) =
+                (record.get(0), record.get(1), record.get(2))
+            else {
+                anyhow::bail!("Invalid seed record: {record:?}");
+            };
+            let Some((x, y)) = coords
+                .strip_prefix('(')
+                .and_then(|coords| coords.strip_suffix(')'))
+                .and_then(|coords| coords.split_once(','))
+            else {
+                anyhow::bail!("Invalid seed coordinates: {coords}");
+            };
+
+            self.add_magnet(
+                DEFAULT_BOARD,
+                x.trim().parse()?,
+                y.trim().parse()?,
+                rotation.parse()?,
+                word.to_string(),
+            );
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    fn notify(&self, magnet_update: PgMagnetUpdate) {
+        // Nobody listening just means nobody cares
+        let _ = self.changes.send(magnet_update);
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn board_bounds(&self, board_id: &str) -> Result<Option<Window>, sqlx::Error> {
+        Ok(self.contents.lock().unwrap().boards.get(board_id).cloned())
+    }
+
+    async fn magnets_in(&self, board_id: &str, shape: &Shape) -> Result<Vec<Magnet>, sqlx::Error> {
+        let contents = self.contents.lock().unwrap();
+        let magnets = contents
+            .magnets
+            .values()
+            .filter(|stored| stored.board_id == board_id)
+            .map(|stored| &stored.magnet)
+            .filter(|magnet| match shape {
+                Shape::Window(window) => window.contains(magnet.x, magnet.y),
+                Shape::Polygon(polygon) => polygon.contains(magnet.x, magnet.y),
+            })
+            .cloned()
+            .collect();
+        Ok(magnets)
+    }
+
+    async fn magnet(&self, board_id: &str, id: i32) -> Result<Option<Magnet>, sqlx::Error> {
+        let contents = self.contents.lock().unwrap();
+        let magnet = contents
+            .magnets
+            .get(&id)
+            .filter(|stored| stored.board_id == board_id)
+            .map(|stored| stored.magnet.clone());
+        Ok(magnet)
+    }
+
+    async fn magnets_at(
+        &self,
+        board_id: &str,
+        window: &Window,
+        at: DateTime<Utc>,
+    ) -> Result<Vec<Magnet>, sqlx::Error> {
+        let contents = self.contents.lock().unwrap();
+
+        let mut first_moves: HashMap<i32, &PastMove> = HashMap::new();
+        for past_move in contents.moves.iter().filter(|m| m.moved_at > at) {
+            first_moves.entry(past_move.magnet_id).or_insert(past_move);
+        }
+
+        let magnets = contents
+            .magnets
+            .values()
+            .filter(|stored| stored.board_id == board_id)
+            .map(|stored| match first_moves.get(&stored.magnet.id) {
+                Some(first_move) => Magnet {
+                    x: first_move.old_x,
+                    y: first_move.old_y,
+                    rotation: first_move.old_rotation,
+                    z_index: first_move.old_z_index,
+                    ..stored.magnet.clone()
+                },
+                None => stored.magnet.clone(),
+            })
+            .filter(|magnet| window.contains(magnet.x, magnet.y))
+            .collect();
+        Ok(magnets)
+    }
+
+    async fn prune_history(&self, before: DateTime<Utc>) -> Result<u64, sqlx::Error> {
+        let mut contents = self.contents.lock().unwrap();
+        let count = contents.moves.len();
+        contents
+            .moves
+            .retain(|past_move| past_move.moved_at >= before);
+        Ok((count - contents.moves.len()) as u64)
+    }
+
+    fn all_magnets(&self) -> BoxStream<'_, Result<(String, Magnet), sqlx::Error>> {
+        let magnets: Vec<_> = self
+            .contents
+            .lock()
+            .unwrap()
+            .magnets
+            .values()
+            .map(|stored| Ok((stored.board_id.clone(), stored.magnet.clone())))
+            .collect();
+        futures_util::stream::iter(magnets).boxed()
+    }
+
+    async fn move_magnet(
+        &self,
+        board_id: &str,
+        update: &ClientMagnetUpdate,
+        session_id: &Uuid,
+    ) -> Result<Option<MoveRecord>, sqlx::Error> {
+        let mut contents = self.contents.lock().unwrap();
+        match contents.magnets.get(&update.id) {
+            Some(stored) if stored.board_id == board_id => {
+                if update
+                    .z_index
+                    .is_some_and(|z_index| z_index != stored.magnet.z_index)
+                {
+                    return Ok(None);
+                }
+            }
+            _ => return Err(sqlx::Error::RowNotFound),
+        }
+
+        let (move_record, magnet_update) =
+            contents.set_position(update.id, update.x, update.y, update.rotation, session_id);
+        // Still holding the lock, so that changes are fed back in order
+        self.notify(magnet_update);
+        Ok(Some(move_record))
+    }
+
+    async fn revert_move(
+        &self,
+        revert: &MoveRecord,
+        session_id: &Uuid,
+    ) -> Result<Option<i64>, sqlx::Error> {
+        let mut contents = self.contents.lock().unwrap();
+        match contents.magnets.get(&revert.id) {
+            Some(stored)
+                if stored.magnet.z_index == revert.z_index
+                    && stored.last_modifier == Some(*session_id) => {}
+            _ => return Ok(None),
+        }
+
+        let (move_record, magnet_update) = contents.set_position(
+            revert.id,
+            revert.old_x,
+            revert.old_y,
+            revert.old_rotation,
+            session_id,
+        );
+        self.notify(magnet_update);
+        Ok(Some(move_record.z_index))
+    }
+
+    async fn save_poem(&self, poem: &SavedPoem, _session_id: &Uuid) -> Result<(), sqlx::Error> {
+        let mut contents = self.contents.lock().unwrap();
+        if contents.poems.contains_key(&poem.slug) {
+            return Err(sqlx::Error::Protocol(format!(
+                "Poem {} already exists",
+                poem.slug
+            )));
+        }
+
+        contents.poems.insert(poem.slug.clone(), poem.clone());
+        Ok(())
+    }
+
+    async fn saved_poem(&self, slug: &str) -> Result<Option<SavedPoem>, sqlx::Error> {
+        Ok(self.contents.lock().unwrap().poems.get(slug).cloned())
+    }
+
+    async fn changes(&self, token: CancellationToken) -> Result<Box<dyn ChangeFeed>, sqlx::Error> {
+        Ok(Box::new(MemoryChangeFeed {
+            rx: self.changes.subscribe(),
+            token,
+        }))
+    }
+
+    async fn ping(&self) -> Result<(), sqlx::Error> {
+        Ok(())
+    }
+
+    async fn close(&self) {}
+}
+
+struct MemoryChangeFeed {
+    rx: broadcast::Receiver<PgMagnetUpdate>,
+    token: CancellationToken,
+}
+
+#[async_trait]
+impl ChangeFeed for MemoryChangeFeed {
+    async fn next(&mut self) -> Option<ChangeEvent> {
+        select! {
+            () = self.token.cancelled() => None,
+            magnet_update = self.rx.recv() => match magnet_update {
+                Ok(magnet_update) => Some(ChangeEvent::Update(magnet_update)),
+                Err(broadcast::error::RecvError::Lagged(_)) => Some(ChangeEvent::Gap),
+                Err(broadcast::error::RecvError::Closed) => None,
+            },
+        }
+    }
+}
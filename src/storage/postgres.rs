@@ -0,0 +1,399 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures_util::{StreamExt as _, TryStreamExt as _, stream::BoxStream};
+use sqlx::{
+    Connection as _, PgPool,
+    postgres::PgListener,
+    types::{
+        Json,
+        chrono::{DateTime, Utc},
+    },
+};
+use tokio::select;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use super::{ChangeFeed, MoveRecord, Storage};
+use crate::{
+    gallery::SavedPoem,
+    geometry::{Shape, Window},
+    protocol::{ClientMagnetUpdate, Magnet},
+    state::ChangeEvent,
+};
+
+const MAGNET_UPDATES_CHANNEL: &str = "magnet_updates";
+
+#[derive(Clone, Debug)]
+pub struct PgStorage {
+    pool: PgPool,
+}
+
+impl PgStorage {
+    pub fn new(pool: PgPool) -> PgStorage {
+        PgStorage { pool }
+    }
+}
+
+#[async_trait]
+impl Storage for PgStorage {
+    #[tracing::instrument(skip(self))]
+    async fn board_bounds(&self, board_id: &str) -> Result<Option<Window>, sqlx::Error> {
+        sqlx::query_as!(
+            Window,
+            r#"SELECT min_x AS x1, min_y AS y1, max_x AS x2, max_y AS y2
+               FROM boards
+               WHERE id = $1"#,
+            board_id
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn magnets_in(&self, board_id: &str, shape: &Shape) -> Result<Vec<Magnet>, sqlx::Error> {
+        match shape {
+            Shape::Window(window) => {
+                sqlx::query_as!(
+                    Magnet,
+                    r#"SELECT id, coords[0]::int AS "x!", coords[1]::int AS "y!", rotation, word, z_index
+                       FROM magnets
+                       WHERE board_id = $5 AND coords <@ Box(Point($1::int, $2::int), Point($3::int, $4::int))"#,
+                    window.x1,
+                    window.y1,
+                    window.x2,
+                    window.y2,
+                    board_id
+                )
+                .fetch_all(&self.pool)
+                .await
+            }
+
+            // what the fuck
+            Shape::Polygon(polygon) => {
+                sqlx::query_as!(
+                    Magnet,
+                    r#"SELECT id, coords[0]::int AS "x!", coords[1]::int AS "y!", rotation, word, z_index
+                        FROM magnets
+                        WHERE board_id = $13 AND coords <@ Polygon('(' ||
+                            '(' || $1::int || ',' || $2::int || '),' ||
+                            '(' || $3::int || ',' || $4::int || '),' ||
+                            '(' || $5::int || ',' || $6::int || '),' ||
+                            '(' || $7::int || ',' || $8::int || '),' ||
+                            '(' || $9::int || ',' || $10::int || '),' ||
+                            '(' || $11::int || ',' || $12::int || ')' ||
+                        ')')"#,
+                    polygon.p1.x,
+                    polygon.p1.y,
+                    polygon.p2.x,
+                    polygon.p2.y,
+                    polygon.p3.x,
+                    polygon.p3.y,
+                    polygon.p4.x,
+                    polygon.p4.y,
+                    polygon.p5.x,
+                    polygon.p5.y,
+                    polygon.p6.x,
+                    polygon.p6.y,
+                    board_id
+                )
+                .fetch_all(&self.pool)
+                .await
+            }
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn magnet(&self, board_id: &str, id: i32) -> Result<Option<Magnet>, sqlx::Error> {
+        sqlx::query_as!(
+            Magnet,
+            r#"SELECT id, coords[0]::int AS "x!", coords[1]::int AS "y!", rotation, word, z_index
+               FROM magnets
+               WHERE board_id = $1 AND id = $2"#,
+            board_id,
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn magnets_at(
+        &self,
+        board_id: &str,
+        window: &Window,
+        at: DateTime<Utc>,
+    ) -> Result<Vec<Magnet>, sqlx::Error> {
+        // A magnet that has moved since `at` was wherever its first move after
+        // `at` took it from, everything else is still where it was. Only
+        // magnets that are in the window now or were at some point since are
+        // looked at, so that the rest of the history can be left alone.
+        sqlx::query_as!(
+            Magnet,
+            r#"WITH candidates AS (
+                   SELECT id
+                   FROM magnets
+                   WHERE board_id = $6
+                       AND coords <@ Box(Point($1::int, $2::int), Point($3::int, $4::int))
+                   UNION
+                   SELECT mm.magnet_id
+                   FROM magnet_moves mm JOIN magnets m ON m.id = mm.magnet_id
+                   WHERE mm.moved_at > $5
+                       AND m.board_id = $6
+                       AND mm.old_coords <@ Box(Point($1::int, $2::int), Point($3::int, $4::int))
+               ),
+               first_moves AS (
+                   SELECT DISTINCT ON (mm.magnet_id) mm.magnet_id, mm.old_coords, mm.old_rotation,
+                          mm.old_z_index
+                   FROM magnet_moves mm JOIN candidates c ON c.id = mm.magnet_id
+                   WHERE mm.moved_at > $5
+                   ORDER BY mm.magnet_id, mm.moved_at, mm.id
+               )
+               SELECT m.id, (COALESCE(f.old_coords, m.coords))[0]::int AS "x!",
+                      (COALESCE(f.old_coords, m.coords))[1]::int AS "y!",
+                      COALESCE(f.old_rotation, m.rotation) AS "rotation!", m.word,
+                      COALESCE(f.old_z_index, m.z_index) AS "z_index!"
+               FROM candidates c
+                   JOIN magnets m ON m.id = c.id
+                   LEFT JOIN first_moves f ON f.magnet_id = m.id
+               WHERE COALESCE(f.old_coords, m.coords)
+                   <@ Box(Point($1::int, $2::int), Point($3::int, $4::int))"#,
+            window.x1,
+            window.y1,
+            window.x2,
+            window.y2,
+            at,
+            board_id
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn prune_history(&self, before: DateTime<Utc>) -> Result<u64, sqlx::Error> {
+        sqlx::query!("DELETE FROM magnet_moves WHERE moved_at < $1", before)
+            .execute(&self.pool)
+            .await
+            .map(|result| result.rows_affected())
+    }
+
+    fn all_magnets(&self) -> BoxStream<'_, Result<(String, Magnet), sqlx::Error>> {
+        sqlx::query!(
+            r#"SELECT id, board_id, coords[0]::int AS "x!", coords[1]::int AS "y!", rotation, word, z_index
+               FROM magnets"#
+        )
+        .fetch(&self.pool)
+        .map_ok(|row| {
+            (
+                row.board_id,
+                Magnet {
+                    id: row.id,
+                    x: row.x,
+                    y: row.y,
+                    rotation: row.rotation,
+                    z_index: row.z_index,
+                    word: row.word,
+                },
+            )
+        })
+        .boxed()
+    }
+
+    #[tracing::instrument(skip(self, session_id))]
+    async fn move_magnet(
+        &self,
+        board_id: &str,
+        update: &ClientMagnetUpdate,
+        session_id: &Uuid,
+    ) -> Result<Option<MoveRecord>, sqlx::Error> {
+        let move_record = sqlx::query_as!(
+            MoveRecord,
+            r#"UPDATE magnets
+               SET coords = Point($1::int, $2::int), rotation = $3, z_index = nextval('magnets_z_index_seq'), last_modifier = $4
+               FROM (SELECT id, coords, rotation, z_index FROM magnets WHERE id = $5 AND board_id = $6 FOR UPDATE) AS old
+               WHERE magnets.id = old.id AND ($7::bigint IS NULL OR old.z_index = $7)
+               RETURNING magnets.id, old.coords[0]::int AS "old_x!", old.coords[1]::int AS "old_y!",
+                         old.rotation AS old_rotation, magnets.z_index"#,
+            update.x,
+            update.y,
+            update.rotation,
+            session_id,
+            update.id,
+            board_id,
+            update.z_index
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match (move_record, update.z_index) {
+            (Some(move_record), _) => return Ok(Some(move_record)),
+            (None, None) => return Err(sqlx::Error::RowNotFound),
+            // Either the magnet isn't there or it has moved on, find out which
+            (None, Some(_)) => {}
+        }
+
+        let exists = sqlx::query_scalar!(
+            r#"SELECT EXISTS (SELECT 1 FROM magnets WHERE id = $1 AND board_id = $2) AS "exists!""#,
+            update.id,
+            board_id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        if exists {
+            Ok(None)
+        } else {
+            Err(sqlx::Error::RowNotFound)
+        }
+    }
+
+    #[tracing::instrument(skip(self, session_id))]
+    async fn revert_move(
+        &self,
+        revert: &MoveRecord,
+        session_id: &Uuid,
+    ) -> Result<Option<i64>, sqlx::Error> {
+        sqlx::query_scalar!(
+            r#"UPDATE magnets
+               SET coords = Point($1::int, $2::int), rotation = $3, z_index = nextval('magnets_z_index_seq'), last_modifier = $4
+               WHERE id = $5 AND z_index = $6 AND last_modifier = $4
+               RETURNING z_index"#,
+            revert.old_x,
+            revert.old_y,
+            revert.old_rotation,
+            session_id,
+            revert.id,
+            revert.z_index
+        )
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    #[tracing::instrument(skip(self, poem, session_id), fields(slug = poem.slug))]
+    async fn save_poem(&self, poem: &SavedPoem, session_id: &Uuid) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"INSERT INTO poems (slug, board_id, min_x, min_y, max_x, max_y, magnets, created_by, created_at)
+               VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)"#,
+            poem.slug,
+            poem.board_id,
+            poem.window.x1,
+            poem.window.y1,
+            poem.window.x2,
+            poem.window.y2,
+            Json(&poem.magnets) as _,
+            session_id,
+            poem.created_at
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn saved_poem(&self, slug: &str) -> Result<Option<SavedPoem>, sqlx::Error> {
+        let row = sqlx::query!(
+            r#"SELECT slug, board_id, min_x, min_y, max_x, max_y,
+                      magnets AS "magnets: Json<Vec<Magnet>>", created_at
+               FROM poems
+               WHERE slug = $1"#,
+            slug
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|row| SavedPoem {
+            slug: row.slug,
+            board_id: row.board_id,
+            window: Window {
+                x1: row.min_x,
+                y1: row.min_y,
+                x2: row.max_x,
+                y2: row.max_y,
+            },
+            magnets: row.magnets.0,
+            created_at: row.created_at,
+        }))
+    }
+
+    async fn changes(&self, token: CancellationToken) -> Result<Box<dyn ChangeFeed>, sqlx::Error> {
+        let listener = connect_change_listener(&self.pool).await?;
+        Ok(Box::new(PgChangeFeed {
+            pool: self.pool.clone(),
+            token,
+            listener,
+        }))
+    }
+
+    async fn ping(&self) -> Result<(), sqlx::Error> {
+        self.pool.acquire().await?.ping().await
+    }
+
+    async fn close(&self) {
+        self.pool.close().await;
+    }
+}
+
+async fn connect_change_listener(pool: &PgPool) -> Result<PgListener, sqlx::Error> {
+    let mut pg_change_listener = PgListener::connect_with(pool).await?;
+    pg_change_listener.listen(MAGNET_UPDATES_CHANNEL).await?;
+    Ok(pg_change_listener)
+}
+
+/// Keeps trying to re-establish the change stream, backing off exponentially.
+/// Returns `None` if the server shuts down in the meantime.
+async fn reconnect_change_listener(pool: &PgPool, token: &CancellationToken) -> Option<PgListener> {
+    const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+    let mut backoff = Duration::from_millis(500);
+    loop {
+        match connect_change_listener(pool).await {
+            Ok(pg_change_listener) => {
+                tracing::info!("Reconnected to Postgres change stream");
+                return Some(pg_change_listener);
+            }
+            Err(sqlx::Error::PoolClosed) => return None,
+            Err(e) => {
+                tracing::warn!("Unable to reconnect to Postgres, retrying in {backoff:?}: {e}");
+            }
+        }
+
+        select! {
+            () = tokio::time::sleep(backoff) => {}
+            () = token.cancelled() => return None,
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Notifications sent by the `notify_change` trigger.
+struct PgChangeFeed {
+    pool: PgPool,
+    token: CancellationToken,
+    listener: PgListener,
+}
+
+#[async_trait]
+impl ChangeFeed for PgChangeFeed {
+    async fn next(&mut self) -> Option<ChangeEvent> {
+        match self.listener.try_recv().await {
+            Ok(Some(msg)) => {
+                let magnet_update = serde_json::from_str(msg.payload())
+                    .expect("Received invalid JSON from postgres");
+                return Some(ChangeEvent::Update(magnet_update));
+            }
+            Ok(None) => {
+                tracing::warn!("Temporarily lost connection to Postgres");
+            }
+            Err(sqlx::Error::PoolClosed) => {
+                return None;
+            }
+            Err(e) => {
+                tracing::error!("Lost connection to Postgres change stream: {e}");
+            }
+        }
+
+        // Any notifications sent while we weren't listening are gone
+        self.listener = reconnect_change_listener(&self.pool, &self.token).await?;
+        Some(ChangeEvent::Gap)
+    }
+}
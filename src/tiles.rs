@@ -0,0 +1,362 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+
+use crate::{
+    geometry::Window,
+    leases::{DragUpdate, HoldUpdate},
+    presence::CursorUpdate,
+    state::{ChangeEvent, PgMagnetUpdate},
+};
+
+/// Width and height of the squares a board is split into for fan-out.
+const TILE_SIZE: i32 = 4096;
+/// Windows spanning more tiles than this are sent every update on the board
+/// instead of being registered tile by tile.
+const MAX_TILES_PER_WINDOW: i64 = 64;
+
+type Tile = (i32, i32);
+
+fn tile_at(x: i32, y: i32) -> Tile {
+    (x.div_euclid(TILE_SIZE), y.div_euclid(TILE_SIZE))
+}
+
+/// What gets fanned out to the sessions looking at part of a board.
+#[derive(Clone, Debug)]
+pub enum BoardEvent {
+    /// From storage, by way of `broadcast_changes`
+    Change(ChangeEvent),
+    /// Someone on this server grabbed or let go of a magnet. These don't come
+    /// from storage, sessions on the same board tell each other directly.
+    Hold(HoldUpdate),
+    /// Someone on this server is dragging a magnet, sent the same way as holds
+    Drag(DragUpdate),
+    Cursor(CursorUpdate),
+}
+
+/// The part of the board a subscriber wants to hear about.
+#[derive(Debug)]
+enum Coverage {
+    Tiles(Vec<Tile>),
+    Everywhere,
+}
+
+impl Coverage {
+    fn of(window: &Window) -> Coverage {
+        if !window.is_valid() {
+            return Coverage::Tiles(Vec::new());
+        }
+
+        let (x1, y1) = tile_at(window.x1, window.y1);
+        let (x2, y2) = tile_at(window.x2, window.y2);
+        let tile_count = (i64::from(x2) - i64::from(x1) + 1) * (i64::from(y2) - i64::from(y1) + 1);
+        if tile_count > MAX_TILES_PER_WINDOW {
+            return Coverage::Everywhere;
+        }
+
+        Coverage::Tiles(
+            (x1..=x2)
+                .flat_map(|x| (y1..=y2).map(move |y| (x, y)))
+                .collect(),
+        )
+    }
+}
+
+#[derive(Debug)]
+struct Subscriber {
+    tx: mpsc::Sender<BoardEvent>,
+    /// Events that didn't fit in `tx`, reported to the session as lag
+    skipped: Arc<AtomicU64>,
+    coverage: Coverage,
+    /// Set while the session's client is disconnected and it may or may not
+    /// come back
+    suspended: bool,
+}
+
+impl Subscriber {
+    fn send(&self, board_event: BoardEvent) {
+        if self.tx.try_send(board_event).is_err() {
+            self.skipped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    next_id: u64,
+    subscribers: HashMap<u64, Subscriber>,
+    tiles: HashMap<Tile, HashSet<u64>>,
+    everywhere: HashSet<u64>,
+}
+
+impl Registry {
+    fn unregister(&mut self, id: u64) -> Option<Subscriber> {
+        let subscriber = self.subscribers.remove(&id)?;
+        match &subscriber.coverage {
+            Coverage::Tiles(tiles) => {
+                for tile in tiles {
+                    if let Some(ids) = self.tiles.get_mut(tile) {
+                        ids.remove(&id);
+                        if ids.is_empty() {
+                            self.tiles.remove(tile);
+                        }
+                    }
+                }
+            }
+            Coverage::Everywhere => {
+                self.everywhere.remove(&id);
+            }
+        }
+        Some(subscriber)
+    }
+
+    fn register(&mut self, id: u64, subscriber: Subscriber) {
+        match &subscriber.coverage {
+            Coverage::Tiles(tiles) => {
+                for tile in tiles {
+                    self.tiles.entry(*tile).or_default().insert(id);
+                }
+            }
+            Coverage::Everywhere => {
+                self.everywhere.insert(id);
+            }
+        }
+        self.subscribers.insert(id, subscriber);
+    }
+}
+
+/// Sessions on a board, indexed by the tiles their windows overlap so that an
+/// update only reaches the sessions that can see it.
+#[derive(Clone, Debug)]
+pub struct TileRegistry {
+    registry: Arc<Mutex<Registry>>,
+    /// How many events can be queued for a session before it's considered to
+    /// be lagging
+    capacity: usize,
+}
+
+impl TileRegistry {
+    pub fn new(capacity: usize) -> TileRegistry {
+        TileRegistry {
+            registry: Default::default(),
+            capacity,
+        }
+    }
+
+    /// Subscribes to nothing until a window is set.
+    pub fn subscribe(&self) -> Subscription {
+        let (tx, rx) = mpsc::channel(self.capacity);
+        let skipped = Arc::new(AtomicU64::new(0));
+
+        let mut registry = self.registry.lock().unwrap();
+        let id = registry.next_id;
+        registry.next_id += 1;
+        registry.register(
+            id,
+            Subscriber {
+                tx,
+                skipped: skipped.clone(),
+                coverage: Coverage::Tiles(Vec::new()),
+                suspended: false,
+            },
+        );
+
+        Subscription {
+            id,
+            rx,
+            skipped,
+            registry: self.clone(),
+        }
+    }
+
+    /// Sends an update to every session whose tiles contain either end of the
+    /// move. Returns how many sessions it was sent to.
+    pub fn publish(&self, magnet_update: PgMagnetUpdate) -> usize {
+        let from_tile = tile_at(magnet_update.old_x, magnet_update.old_y);
+        let to_tile = tile_at(magnet_update.new_x, magnet_update.new_y);
+        self.publish_between(
+            from_tile,
+            to_tile,
+            BoardEvent::Change(ChangeEvent::Update(magnet_update)),
+        )
+    }
+
+    /// Sends a grab or release to every session whose tiles contain the magnet.
+    pub fn publish_hold(&self, hold_update: HoldUpdate) -> usize {
+        let tile = tile_at(hold_update.x, hold_update.y);
+        self.publish_between(tile, tile, BoardEvent::Hold(hold_update))
+    }
+
+    /// Sends a drag to every session whose tiles contain where the magnet was
+    /// or where it's been dragged to.
+    pub fn publish_drag(&self, drag_update: DragUpdate) -> usize {
+        let from_tile = tile_at(drag_update.old_x, drag_update.old_y);
+        let to_tile = tile_at(drag_update.x, drag_update.y);
+        self.publish_between(from_tile, to_tile, BoardEvent::Drag(drag_update))
+    }
+
+    /// Sends a cursor to every session whose tiles contain where it was or
+    /// where it is now.
+    pub fn publish_cursor(&self, cursor_update: CursorUpdate) -> usize {
+        let Some((x, y)) = cursor_update.new.or(cursor_update.old) else {
+            return 0;
+        };
+        let (old_x, old_y) = cursor_update.old.unwrap_or((x, y));
+        self.publish_between(
+            tile_at(old_x, old_y),
+            tile_at(x, y),
+            BoardEvent::Cursor(cursor_update),
+        )
+    }
+
+    fn publish_between(&self, from_tile: Tile, to_tile: Tile, board_event: BoardEvent) -> usize {
+        let registry = self.registry.lock().unwrap();
+
+        let from = registry.tiles.get(&from_tile);
+        let to = (to_tile != from_tile)
+            .then(|| registry.tiles.get(&to_tile))
+            .flatten();
+
+        let recipients = registry
+            .everywhere
+            .iter()
+            .chain(from.into_iter().flatten())
+            .chain(
+                to.into_iter()
+                    .flatten()
+                    .filter(|id| !from.is_some_and(|from| from.contains(id))),
+            );
+
+        let mut sent = 0;
+        for id in recipients {
+            let subscriber = &registry.subscribers[id];
+            // Nobody's there to see holds, drags or cursors
+            if subscriber.suspended && !matches!(board_event, BoardEvent::Change(_)) {
+                continue;
+            }
+            subscriber.send(board_event.clone());
+            sent += 1;
+        }
+        sent
+    }
+
+    /// How many events are waiting to be sent to each session.
+    pub fn queue_lengths(&self) -> Vec<usize> {
+        self.registry
+            .lock()
+            .unwrap()
+            .subscribers
+            .values()
+            .map(|subscriber| subscriber.tx.max_capacity() - subscriber.tx.capacity())
+            .collect()
+    }
+
+    /// Sends a gap to every session, wherever it's looking.
+    pub fn publish_gap(&self) {
+        for subscriber in self.registry.lock().unwrap().subscribers.values() {
+            subscriber.send(BoardEvent::Change(ChangeEvent::Gap));
+        }
+    }
+}
+
+/// A session's feed of changes to the part of the board it's looking at.
+/// Unsubscribes when dropped.
+#[derive(Debug)]
+pub struct Subscription {
+    id: u64,
+    rx: mpsc::Receiver<BoardEvent>,
+    skipped: Arc<AtomicU64>,
+    registry: TileRegistry,
+}
+
+impl Subscription {
+    /// Tells this subscription apart from others on the same board.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// How many other sessions are looking at tiles that `window` overlaps,
+    /// not counting suspended ones.
+    pub fn viewers_near(&self, window: &Window) -> usize {
+        let registry = self.registry.registry.lock().unwrap();
+        let tiles = match Coverage::of(window) {
+            Coverage::Tiles(tiles) => tiles,
+            Coverage::Everywhere => {
+                return registry
+                    .subscribers
+                    .iter()
+                    .filter(|(id, subscriber)| {
+                        **id != self.id
+                            && !subscriber.suspended
+                            && !matches!(&subscriber.coverage, Coverage::Tiles(tiles) if tiles.is_empty())
+                    })
+                    .count();
+            }
+        };
+
+        let mut viewers: HashSet<u64> = registry.everywhere.clone();
+        for tile in &tiles {
+            if let Some(ids) = registry.tiles.get(tile) {
+                viewers.extend(ids);
+            }
+        }
+        viewers.remove(&self.id);
+        viewers
+            .iter()
+            .filter(|id| !registry.subscribers[id].suspended)
+            .count()
+    }
+
+    /// Marks the session as disconnected, or as back again.
+    pub fn set_suspended(&self, suspended: bool) {
+        let mut registry = self.registry.registry.lock().unwrap();
+        if let Some(subscriber) = registry.subscribers.get_mut(&self.id) {
+            subscriber.suspended = suspended;
+        }
+    }
+
+    /// Moves the subscription to the tiles overlapping `window`.
+    pub fn set_window(&self, window: &Window) {
+        let mut registry = self.registry.registry.lock().unwrap();
+        if let Some(mut subscriber) = registry.unregister(self.id) {
+            subscriber.coverage = Coverage::of(window);
+            registry.register(self.id, subscriber);
+        }
+    }
+
+    /// Waits for the next change, like `broadcast::Receiver::recv`. Events
+    /// dropped because the session fell behind are reported as
+    /// `RecvError::Lagged`, after which the session has to resynchronize.
+    pub async fn recv(&mut self) -> Result<BoardEvent, RecvError> {
+        let skipped = self.skipped.swap(0, Ordering::Relaxed);
+        if skipped > 0 {
+            // Anything still queued is older than the resync and can go
+            while self.rx.try_recv().is_ok() {}
+            return Err(RecvError::Lagged(skipped));
+        }
+
+        self.rx.recv().await.ok_or(RecvError::Closed)
+    }
+
+    /// Like `recv`, but skipping holds, drags and cursors, which are only of
+    /// use to a connected client.
+    pub async fn recv_change(&mut self) -> Result<ChangeEvent, RecvError> {
+        loop {
+            if let BoardEvent::Change(change_event) = self.recv().await? {
+                return Ok(change_event);
+            }
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.registry.registry.lock().unwrap().unregister(self.id);
+    }
+}
@@ -1,108 +1,65 @@
-use std::time::{Duration, Instant};
+#[cfg(test)]
+mod tests;
+
+use std::{
+    collections::VecDeque,
+    net::IpAddr,
+    sync::{Arc, atomic::Ordering},
+    time::{Duration, Instant},
+};
 
 use futures_util::{SinkExt as _, StreamExt};
-use serde::{Deserialize, Serialize};
-use sqlx::PgPool;
-use tokio::{net::TcpStream, select, time::timeout};
+use sqlx::types::chrono::{DateTime, Utc};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    select,
+    sync::broadcast::error::RecvError,
+    time::timeout,
+};
 use tokio_websockets::{Message, WebSocketStream};
 use tracing::{Instrument, Level};
 use uuid::Uuid;
 
 use crate::{
+    board::Board,
+    cache::MagnetCache,
     error::FridgeError,
+    gallery::{self, SavedPoem},
     geometry::{Shape, Window},
-    state::{AppState, PgMagnetUpdate},
+    leases::{DragUpdate, HoldUpdate, Leases},
+    metrics::Metrics,
+    poems,
+    presence::CursorUpdate,
+    protocol::{self, ClientRequest, ClientUpdate, LocationUpdate, Magnet, MagnetUpdate, Protocol},
+    rate_limit::{RequestKind, SessionRateLimiter},
+    resume::{self, ResumedSession},
+    state::{AppState, ChangeEvent, PgMagnetUpdate},
+    storage::{MoveRecord, Storage},
+    tiles::{BoardEvent, Subscription},
 };
 
-type WsStream = WebSocketStream<TcpStream>;
+/// What a session's WebSocket runs over: a client's connection, or a pipe
+/// in tests.
+pub trait Socket: AsyncRead + AsyncWrite + Unpin + Send {}
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Magnet {
-    id: i32,
-    x: i32,
-    y: i32,
-    rotation: i32,
-    z_index: i64,
-    word: String,
-}
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Socket for S {}
 
-#[derive(Debug, Serialize, Deserialize)]
-struct LocationUpdate {
-    id: i32,
-    x: i32,
-    y: i32,
-    rotation: i32,
-    z_index: i64,
-}
-
-#[derive(Debug, Serialize)]
-#[serde(untagged)]
-enum MagnetUpdate {
-    Create(Magnet),
-    Move(LocationUpdate),
-    Remove(i32),
-    CanvasUpdate(Vec<Magnet>),
-    SessionIdUpdate(String),
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct ClientMagnetUpdate {
-    is_magnet_update: bool,
-    id: i32,
-    x: i32,
-    y: i32,
-    rotation: i32,
-}
-
-impl ClientMagnetUpdate {
-    fn is_valid(&self, window: &Window) -> bool {
-        const MAX_MAGNET_ID: i32 = 22_000_000;
-
-        if self.id > MAX_MAGNET_ID {
-            tracing::trace!("Invalid id: {}", self.id);
-            return false;
-        }
-
-        if !(-360..=360).contains(&self.rotation) {
-            tracing::trace!("Invalid rotation: {}", self.rotation);
-            return false;
-        }
-
-        if !(window.x1 - 100..=window.x2 + 100).contains(&self.x)
-            || !(window.y1 - 100..=window.y2 + 100).contains(&self.y)
-        {
-            tracing::trace!(
-                "Invalid location outside window bounds: ({}, {})",
-                self.x,
-                self.y
-            );
-            return false;
-        }
-
-        if !(-500_000..=500_000).contains(&self.x) || !(-500_000..=500_000).contains(&self.y) {
-            tracing::trace!(
-                "Invalid update outside world bounds: ({}, {})",
-                self.x,
-                self.y
-            );
-            return false;
-        }
-
-        true
+async fn send_update<S: Socket>(
+    ws_stream: &mut WebSocketStream<S>,
+    protocol: Protocol,
+    update: &MagnetUpdate,
+) -> Result<(), tokio_websockets::Error> {
+    if let Some(buf) = protocol.encode(update) {
+        ws_stream.send(Message::binary(buf)).await?;
     }
-}
-
-#[derive(Debug, Deserialize)]
-#[serde(untagged)]
-enum ClientUpdate {
-    Window(Window),
-    Magnet(ClientMagnetUpdate),
+    Ok(())
 }
 
 // TODO attach timestamp?
 #[tracing::instrument(skip(ws_stream, session_id))]
-async fn send_relevant_update(
-    ws_stream: &mut WsStream,
+async fn send_relevant_update<S: Socket>(
+    ws_stream: &mut WebSocketStream<S>,
+    protocol: Protocol,
     client_window: &Window,
     magnet_update: PgMagnetUpdate,
     session_id: &Uuid,
@@ -120,8 +77,7 @@ async fn send_relevant_update(
                 z_index: magnet_update.z_index,
             });
 
-            let buf = rmp_serde::to_vec(&location_update).unwrap();
-            ws_stream.send(Message::binary(buf)).await?;
+            send_update(ws_stream, protocol, &location_update).await?;
         } else {
             tracing::trace!("Magnet moved into window bounds, sending creation update");
             let create_update = MagnetUpdate::Create(Magnet {
@@ -133,155 +89,692 @@ async fn send_relevant_update(
                 word: magnet_update.word,
             });
 
-            let buf = rmp_serde::to_vec(&create_update).unwrap();
-            ws_stream.send(Message::binary(buf)).await?;
+            send_update(ws_stream, protocol, &create_update).await?;
         }
         Ok(true)
     } else if client_window.contains(magnet_update.old_x, magnet_update.old_y) {
         tracing::trace!("Magnet moved outside of window bounds, sending removal update");
-        let remove_update = MagnetUpdate::Remove(magnet_update.id);
-
-        let buf = rmp_serde::to_vec(&remove_update).unwrap();
-        ws_stream.send(Message::binary(buf)).await?;
+        let remove_update = MagnetUpdate::Remove {
+            id: magnet_update.id,
+        };
+        send_update(ws_stream, protocol, &remove_update).await?;
         Ok(true)
     } else {
         Ok(false)
     }
 }
 
-#[tracing::instrument(skip(ws_stream, postgres))]
-async fn send_new_magnets(
-    ws_stream: &mut WsStream,
+#[tracing::instrument(skip(ws_stream, session_id))]
+async fn send_relevant_hold<S: Socket>(
+    ws_stream: &mut WebSocketStream<S>,
+    protocol: Protocol,
+    client_window: &Window,
+    hold_update: HoldUpdate,
+    session_id: &Uuid,
+) -> Result<(), tokio_websockets::Error> {
+    // The holder knows, everyone else only needs to if they can see it
+    if hold_update.holder == *session_id || !client_window.contains(hold_update.x, hold_update.y) {
+        return Ok(());
+    }
+
+    let update = match hold_update.expires_in {
+        Some(expires_in) => MagnetUpdate::Held {
+            id: hold_update.id,
+            expires_in_ms: expires_in.as_millis().try_into().unwrap_or(u64::MAX),
+        },
+        None => MagnetUpdate::Released { id: hold_update.id },
+    };
+    send_update(ws_stream, protocol, &update).await
+}
+
+#[tracing::instrument(skip(ws_stream, session_id))]
+async fn send_relevant_drag<S: Socket>(
+    ws_stream: &mut WebSocketStream<S>,
+    protocol: Protocol,
+    client_window: &Window,
+    drag_update: DragUpdate,
+    session_id: &Uuid,
+) -> Result<(), tokio_websockets::Error> {
+    if drag_update.dragger == *session_id
+        || !(client_window.contains(drag_update.x, drag_update.y)
+            || client_window.contains(drag_update.old_x, drag_update.old_y))
+    {
+        return Ok(());
+    }
+
+    let dragged = MagnetUpdate::Dragged {
+        id: drag_update.id,
+        x: drag_update.x,
+        y: drag_update.y,
+        rotation: drag_update.rotation,
+    };
+    send_update(ws_stream, protocol, &dragged).await
+}
+
+#[tracing::instrument(skip(ws_stream))]
+async fn send_relevant_cursor<S: Socket>(
+    ws_stream: &mut WebSocketStream<S>,
+    protocol: Protocol,
+    client_window: &Window,
+    cursor_update: CursorUpdate,
+    viewer: u64,
+) -> Result<(), tokio_websockets::Error> {
+    if cursor_update.viewer == viewer {
+        return Ok(());
+    }
+
+    let in_window =
+        |position: Option<(i32, i32)>| position.is_some_and(|(x, y)| client_window.contains(x, y));
+    let update = match cursor_update.new {
+        Some((x, y)) if client_window.contains(x, y) => MagnetUpdate::Cursor {
+            viewer: cursor_update.viewer,
+            x,
+            y,
+        },
+        _ if in_window(cursor_update.old) => MagnetUpdate::CursorGone {
+            viewer: cursor_update.viewer,
+        },
+        _ => return Ok(()),
+    };
+    send_update(ws_stream, protocol, &update).await
+}
+
+async fn magnets_in(
+    board_id: &str,
+    shape: &Shape,
+    magnet_cache: Option<&MagnetCache>,
+    storage: &dyn Storage,
+) -> Result<Vec<Magnet>, sqlx::Error> {
+    match magnet_cache {
+        Some(magnet_cache) => Ok(magnet_cache.magnets_in(board_id, shape)),
+        None => storage.magnets_in(board_id, shape).await,
+    }
+}
+
+async fn magnet(
+    board_id: &str,
+    id: i32,
+    magnet_cache: Option<&MagnetCache>,
+    storage: &dyn Storage,
+) -> Result<Option<Magnet>, sqlx::Error> {
+    match magnet_cache {
+        Some(magnet_cache) => Ok(magnet_cache.magnet(board_id, id)),
+        None => storage.magnet(board_id, id).await,
+    }
+}
+
+#[tracing::instrument(skip(ws_stream, magnet_cache, storage, metrics))]
+async fn send_new_magnets<S: Socket>(
+    ws_stream: &mut WebSocketStream<S>,
+    protocol: Protocol,
+    board_id: &str,
     shape: &Shape,
-    postgres: &PgPool,
+    magnet_cache: Option<&MagnetCache>,
+    storage: &dyn Storage,
+    metrics: &Metrics,
 ) -> Result<(), FridgeError> {
-    let magnets = match shape {
-        Shape::Window(window) => sqlx::query_as!(
-            Magnet,
-            r#"SELECT id, coords[0]::int AS "x!", coords[1]::int AS "y!", rotation, word, z_index
-               FROM magnets
-               WHERE coords <@ Box(Point($1::int, $2::int), Point($3::int, $4::int))"#,
-            window.x1,
-            window.y1,
-            window.x2,
-            window.y2
-        )
-        .fetch_all(postgres)
-        .await?,
-
-        // what the fuck
-        Shape::Polygon(polygon) => sqlx::query_as!(
-            Magnet,
-            r#"SELECT id, coords[0]::int AS "x!", coords[1]::int AS "y!", rotation, word, z_index
-                FROM magnets
-                WHERE coords <@ Polygon('(' ||
-                    '(' || $1::int || ',' || $2::int || '),' ||
-                    '(' || $3::int || ',' || $4::int || '),' ||
-                    '(' || $5::int || ',' || $6::int || '),' ||
-                    '(' || $7::int || ',' || $8::int || '),' ||
-                    '(' || $9::int || ',' || $10::int || '),' ||
-                    '(' || $11::int || ',' || $12::int || ')' ||
-                ')')"#,
-            polygon.p1.x,
-            polygon.p1.y,
-            polygon.p2.x,
-            polygon.p2.y,
-            polygon.p3.x,
-            polygon.p3.y,
-            polygon.p4.x,
-            polygon.p4.y,
-            polygon.p5.x,
-            polygon.p5.y,
-            polygon.p6.x,
-            polygon.p6.y
-        )
-        .fetch_all(postgres)
-        .await?,
-    };
+    let started = Instant::now();
+    let magnets = magnets_in(board_id, shape, magnet_cache, storage).await?;
+    metrics.record_query(shape, started.elapsed());
 
-    let buf = rmp_serde::to_vec(&MagnetUpdate::CanvasUpdate(magnets)).unwrap();
-    ws_stream.send(Message::binary(buf)).await?;
+    send_update(ws_stream, protocol, &MagnetUpdate::CanvasUpdate { magnets }).await?;
     Ok(())
 }
 
-#[tracing::instrument(skip(session_id, postgres))]
-async fn update_magnet(
-    update: ClientMagnetUpdate,
-    session_id: &Uuid,
-    postgres: &PgPool,
+#[tracing::instrument(skip(ws_stream, magnet_cache, storage))]
+async fn send_poems<S: Socket>(
+    ws_stream: &mut WebSocketStream<S>,
+    protocol: Protocol,
+    board_id: &str,
+    window: &Window,
+    magnet_cache: Option<&MagnetCache>,
+    storage: &dyn Storage,
 ) -> Result<(), FridgeError> {
-    sqlx::query!(
-        r#"UPDATE magnets
-           SET coords = Point($1::int, $2::int), rotation = $3, z_index = nextval('magnets_z_index_seq'), last_modifier = $4
-           WHERE id = $5"#,
-        update.x,
-        update.y,
-        update.rotation,
-        session_id,
-        update.id
+    let magnets = magnets_in(
+        board_id,
+        &Shape::Window(window.clone()),
+        magnet_cache,
+        storage,
     )
-    .execute(postgres)
     .await?;
 
+    let poems = poems::detect(&magnets);
+    tracing::trace!("Found {} poems", poems.len());
+    send_update(ws_stream, protocol, &MagnetUpdate::Poems { poems }).await?;
     Ok(())
 }
 
-#[tracing::instrument(skip(ws_stream, session_id))]
-async fn handle_websocket_binary(
-    payload: tokio_websockets::Payload,
-    client_window: &mut Window,
-    ws_stream: &mut WsStream,
-    state: &AppState,
+/// Saves the magnets in `window` as they are now, returning the slug the poem
+/// was saved under.
+#[tracing::instrument(skip(session_id, magnet_cache, storage))]
+async fn save_poem(
+    board_id: &str,
+    window: &Window,
     session_id: &Uuid,
+    magnet_cache: Option<&MagnetCache>,
+    storage: &dyn Storage,
+) -> Result<String, FridgeError> {
+    let magnets = magnets_in(
+        board_id,
+        &Shape::Window(window.clone()),
+        magnet_cache,
+        storage,
+    )
+    .await?;
+
+    let poem = SavedPoem {
+        slug: gallery::new_slug(),
+        board_id: board_id.to_string(),
+        window: window.clone(),
+        magnets,
+        created_at: Utc::now(),
+    };
+    storage.save_poem(&poem, session_id).await?;
+
+    tracing::debug!("Saved {} magnets as poem {}", poem.magnets.len(), poem.slug);
+    Ok(poem.slug)
+}
+
+#[tracing::instrument(skip(ws_stream, storage))]
+async fn send_historical_magnets<S: Socket>(
+    ws_stream: &mut WebSocketStream<S>,
+    protocol: Protocol,
+    board_id: &str,
+    window: &Window,
+    at: DateTime<Utc>,
+    storage: &dyn Storage,
 ) -> Result<(), FridgeError> {
-    let client_update = rmp_serde::from_slice(&payload)?;
+    let magnets = storage.magnets_at(board_id, window, at).await?;
+
+    let history_update = MagnetUpdate::HistoryUpdate {
+        at: at.timestamp_millis(),
+        magnets,
+    };
+    send_update(ws_stream, protocol, &history_update).await?;
+    Ok(())
+}
+
+/// How many of a session's moves are remembered for undoing.
+const MAX_UNDO_MOVES: usize = 20;
+
+/// How often a session's drags are passed on to everyone else. Anything in
+/// between is dropped, it'll be out of date soon enough anyway.
+const DRAG_INTERVAL: Duration = Duration::from_millis(50);
+/// Same as `DRAG_INTERVAL`, for cursors.
+const CURSOR_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Takes back the session's last `count` moves. Magnets that have been moved
+/// by someone else since, or that someone else is holding, are left alone.
+#[tracing::instrument(skip(undo_stack, session_id, leases, storage))]
+async fn undo_moves(
+    undo_stack: &mut VecDeque<MoveRecord>,
+    count: usize,
+    session_id: &Uuid,
+    leases: &Leases,
+    storage: &dyn Storage,
+) -> Result<usize, FridgeError> {
+    let count = count.min(undo_stack.len());
+
+    // Collapse repeated moves of the same magnet into one revert, back to
+    // where it was before the oldest of them
+    let mut reverts: Vec<MoveRecord> = Vec::new();
+    for move_record in undo_stack.drain(undo_stack.len() - count..).rev() {
+        match reverts.iter_mut().find(|r| r.id == move_record.id) {
+            Some(revert) => {
+                revert.old_x = move_record.old_x;
+                revert.old_y = move_record.old_y;
+                revert.old_rotation = move_record.old_rotation;
+            }
+            None => reverts.push(move_record),
+        }
+    }
+
+    let mut reverted = 0;
+    for revert in reverts {
+        if !leases.can_move(revert.id, revert.old_x, revert.old_y, session_id) {
+            tracing::debug!(
+                "Magnet {} is being held by someone else, not undoing",
+                revert.id
+            );
+            continue;
+        }
+
+        let z_index = storage.revert_move(&revert, session_id).await?;
+
+        let Some(z_index) = z_index else {
+            tracing::debug!(
+                "Magnet {} was moved by someone else, not undoing",
+                revert.id
+            );
+            continue;
+        };
+        reverted += 1;
+
+        // The magnet is back where the session's previous move of it left
+        // it, so that move can still be undone later on
+        if let Some(previous) = undo_stack.iter_mut().rev().find(|r| r.id == revert.id) {
+            previous.z_index = z_index;
+        }
+    }
+
+    Ok(reverted)
+}
+
+/// What a request that went through has to tell the client, along with the
+/// `Ack`.
+#[derive(Debug, Default)]
+struct Outcome {
+    /// The `z_index` a moved magnet ended up with
+    z_index: Option<i64>,
+    /// How many magnets an undo put back
+    reverted: Option<usize>,
+}
 
+/// Carries out a request, returning what the client should be told about it.
+async fn handle_client_update<S: Socket>(
+    client_update: ClientUpdate,
+    session_state: &mut SessionState<S>,
+    state: &AppState,
+) -> Result<Outcome, FridgeError> {
     match client_update {
         ClientUpdate::Window(window_update) => {
             if !window_update.is_valid() {
                 return Err(FridgeError::OutOfBounds(format!("{window_update:?}")));
             }
 
-            let difference = client_window.difference(&window_update);
+            let difference = session_state.client_window.difference(&window_update);
 
             let Some(difference) = difference else {
                 // ignoring window non-change
                 tracing::trace!("Window did not actually change since last update, ignoring");
-                return Ok(());
+                return Ok(Outcome::default());
             };
 
-            *client_window = window_update.clamp();
+            session_state.client_window = window_update.clamp();
+            session_state.rx.set_window(&session_state.client_window);
 
-            send_new_magnets(ws_stream, &difference, &state.postgres).await?;
+            send_new_magnets(
+                &mut session_state.ws_stream,
+                session_state.protocol,
+                &session_state.board.id,
+                &difference,
+                state.magnet_cache.as_deref(),
+                &*state.storage,
+                &state.metrics,
+            )
+            .await?;
         }
         ClientUpdate::Magnet(magnet_update) => {
-            if !magnet_update.is_valid(client_window) {
+            if !protocol::is_valid_placement(
+                magnet_update.id,
+                magnet_update.x,
+                magnet_update.y,
+                magnet_update.rotation,
+                &session_state.client_window,
+                &session_state.board.bounds,
+            ) {
                 return Err(FridgeError::OutOfBounds(format!("{magnet_update:?}")));
             }
+            if !session_state.board.leases.can_move(
+                magnet_update.id,
+                magnet_update.x,
+                magnet_update.y,
+                &session_state.session_id,
+            ) {
+                return Err(FridgeError::Held);
+            }
+
+            let Some(move_record) = state
+                .storage
+                .move_magnet(
+                    &session_state.board.id,
+                    &magnet_update,
+                    &session_state.session_id,
+                )
+                .await?
+            else {
+                return Err(FridgeError::Conflict);
+            };
+            let z_index = move_record.z_index;
+            state.metrics.magnets_moved.fetch_add(1, Ordering::Relaxed);
+
+            if session_state.undo_stack.len() == MAX_UNDO_MOVES {
+                session_state.undo_stack.pop_front();
+            }
+            session_state.undo_stack.push_back(move_record);
+            return Ok(Outcome {
+                z_index: Some(z_index),
+                ..Outcome::default()
+            });
+        }
+        ClientUpdate::Undo { count } => {
+            let reverted = undo_moves(
+                &mut session_state.undo_stack,
+                count,
+                &session_state.session_id,
+                &session_state.board.leases,
+                &*state.storage,
+            )
+            .await?;
+            state
+                .metrics
+                .magnets_moved
+                .fetch_add(reverted as u64, Ordering::Relaxed);
+            return Ok(Outcome {
+                reverted: Some(reverted),
+                ..Outcome::default()
+            });
+        }
+        ClientUpdate::History { at } => {
+            // Anything older would have to go through too much of the history
+            let retention = state.history_retention.as_millis();
+            let oldest = Utc::now()
+                .timestamp_millis()
+                .saturating_sub(retention.try_into().unwrap_or(i64::MAX));
+            let Some(at) = DateTime::from_timestamp_millis(at).filter(|_| at >= oldest) else {
+                return Err(FridgeError::OutOfBounds(format!("{client_update:?}")));
+            };
+
+            send_historical_magnets(
+                &mut session_state.ws_stream,
+                session_state.protocol,
+                &session_state.board.id,
+                &session_state.client_window,
+                at,
+                &*state.storage,
+            )
+            .await?;
+        }
+        ClientUpdate::Grab { id } => {
+            let board = &session_state.board;
+            let Some(magnet) = magnet(
+                &board.id,
+                id,
+                state.magnet_cache.as_deref(),
+                &*state.storage,
+            )
+            .await?
+            else {
+                return Err(sqlx::Error::RowNotFound.into());
+            };
+            if !session_state.client_window.contains(magnet.x, magnet.y) {
+                return Err(FridgeError::OutOfBounds(format!("{client_update:?}")));
+            }
+
+            let hold_update =
+                board
+                    .leases
+                    .grab(id, magnet.x, magnet.y, &session_state.session_id)?;
+            board.subscriptions.publish_hold(hold_update);
+        }
+        ClientUpdate::Release { id } => {
+            let board = &session_state.board;
+            if let Some(hold_update) = board.leases.release(id, &session_state.session_id) {
+                board.subscriptions.publish_hold(hold_update);
+            }
+        }
+        ClientUpdate::Drag { id, x, y, rotation } => {
+            if !protocol::is_valid_placement(
+                id,
+                x,
+                y,
+                rotation,
+                &session_state.client_window,
+                &session_state.board.bounds,
+            ) {
+                return Err(FridgeError::OutOfBounds(format!("{client_update:?}")));
+            }
+
+            let now = Instant::now();
+            if session_state
+                .last_drag
+                .is_some_and(|sent_at| now.duration_since(sent_at) < DRAG_INTERVAL)
+            {
+                tracing::trace!("Dropping drag sent too soon after the last one");
+                return Ok(Outcome::default());
+            }
+
+            let board = &session_state.board;
+            let (old_x, old_y) = board.leases.drag(id, x, y, &session_state.session_id)?;
+
+            let drag_update = DragUpdate {
+                id,
+                old_x,
+                old_y,
+                x,
+                y,
+                rotation,
+                dragger: session_state.session_id,
+            };
+            session_state.last_drag = Some(now);
+            board.subscriptions.publish_drag(drag_update);
+        }
+        ClientUpdate::Cursor { x, y } => {
+            if !session_state.client_window.contains(x, y)
+                || !session_state.board.bounds.contains(x, y)
+            {
+                return Err(FridgeError::OutOfBounds(format!("{client_update:?}")));
+            }
+
+            let now = Instant::now();
+            let old = match session_state.last_cursor {
+                Some((sent_at, _)) if now.duration_since(sent_at) < CURSOR_INTERVAL => {
+                    return Ok(Outcome::default());
+                }
+                Some((_, old)) => Some(old),
+                None => None,
+            };
+
+            session_state.last_cursor = Some((now, (x, y)));
+            session_state
+                .board
+                .subscriptions
+                .publish_cursor(CursorUpdate {
+                    viewer: session_state.rx.id(),
+                    old,
+                    new: Some((x, y)),
+                });
+        }
+        ClientUpdate::Poems => {
+            if !session_state.client_window.is_valid() {
+                return Err(FridgeError::OutOfBounds(format!("{client_update:?}")));
+            }
+
+            send_poems(
+                &mut session_state.ws_stream,
+                session_state.protocol,
+                &session_state.board.id,
+                &session_state.client_window,
+                state.magnet_cache.as_deref(),
+                &*state.storage,
+            )
+            .await?;
+        }
+        ClientUpdate::SavePoem(window) => {
+            let size = |from: i32, to: i32| i64::from(to) - i64::from(from);
+            if !window.is_valid()
+                || size(window.x1, window.x2) > i64::from(gallery::MAX_POEM_SIZE)
+                || size(window.y1, window.y2) > i64::from(gallery::MAX_POEM_SIZE)
+            {
+                return Err(FridgeError::OutOfBounds(format!("{window:?}")));
+            }
+
+            let slug = save_poem(
+                &session_state.board.id,
+                &window,
+                &session_state.session_id,
+                state.magnet_cache.as_deref(),
+                &*state.storage,
+            )
+            .await?;
+            send_update(
+                &mut session_state.ws_stream,
+                session_state.protocol,
+                &MagnetUpdate::PoemSaved { slug },
+            )
+            .await?;
+        }
+        ClientUpdate::Viewers => {
+            let viewers = MagnetUpdate::Viewers {
+                count: session_state.rx.viewers_near(&session_state.client_window),
+            };
+            send_update(
+                &mut session_state.ws_stream,
+                session_state.protocol,
+                &viewers,
+            )
+            .await?;
+        }
+    }
+
+    Ok(Outcome::default())
+}
 
-            update_magnet(magnet_update, session_id, &state.postgres).await?;
+#[tracing::instrument(skip(payload, session_state))]
+async fn handle_websocket_binary<S: Socket>(
+    payload: tokio_websockets::Payload,
+    session_state: &mut SessionState<S>,
+    state: &AppState,
+) -> Result<(), FridgeError> {
+    let ClientRequest {
+        request_id,
+        update: client_update,
+    } = session_state.protocol.decode(&payload)?;
+
+    let request_kind = match client_update {
+        ClientUpdate::Window(_)
+        | ClientUpdate::History { .. }
+        | ClientUpdate::Viewers
+        | ClientUpdate::Poems => Some(RequestKind::WindowUpdate),
+        ClientUpdate::Magnet(_)
+        | ClientUpdate::Undo { .. }
+        | ClientUpdate::Grab { .. }
+        | ClientUpdate::Release { .. }
+        | ClientUpdate::SavePoem(_) => Some(RequestKind::Move),
+        // Throttled by dropping them instead
+        ClientUpdate::Drag { .. } | ClientUpdate::Cursor { .. } => None,
+    };
+    let magnet_id = match &client_update {
+        ClientUpdate::Magnet(magnet_update) => Some(magnet_update.id),
+        ClientUpdate::Grab { id, .. }
+        | ClientUpdate::Release { id }
+        | ClientUpdate::Drag { id, .. } => Some(*id),
+        ClientUpdate::Window(_)
+        | ClientUpdate::Undo { .. }
+        | ClientUpdate::History { .. }
+        | ClientUpdate::Cursor { .. }
+        | ClientUpdate::Viewers
+        | ClientUpdate::Poems
+        | ClientUpdate::SavePoem(_) => None,
+    };
+
+    if let Some(request_kind) = request_kind
+        && let Err(retry_after) = session_state.rate_limiter.check(request_kind)
+    {
+        state.metrics.record_rate_limited(request_kind);
+        if session_state.rate_limiter.record_rejection() {
+            return Err(FridgeError::Abusive);
         }
+
+        let rate_limited = MagnetUpdate::RateLimited {
+            request_id,
+            id: magnet_id,
+            retry_after_ms: retry_after
+                .as_nanos()
+                .div_ceil(1_000_000)
+                .try_into()
+                .unwrap_or(u64::MAX),
+        };
+        send_update(
+            &mut session_state.ws_stream,
+            session_state.protocol,
+            &rate_limited,
+        )
+        .await?;
+        return Err(FridgeError::RateLimited);
     }
 
+    let response = match handle_client_update(client_update, session_state, state).await {
+        Ok(outcome) => match request_id {
+            Some(request_id) => MagnetUpdate::Ack {
+                request_id,
+                z_index: outcome.z_index,
+                reverted: outcome.reverted,
+            },
+            None => return Ok(()),
+        },
+        // Legacy clients can't be told what went wrong. A move they lost out
+        // on is undone by showing them where the magnet really is.
+        Err(FridgeError::Held | FridgeError::Conflict)
+            if session_state.protocol == Protocol::Legacy
+                && let Some(id) = magnet_id
+                && let Some(magnet) = magnet(
+                    &session_state.board.id,
+                    id,
+                    state.magnet_cache.as_deref(),
+                    &*state.storage,
+                )
+                .await? =>
+        {
+            MagnetUpdate::Move(LocationUpdate {
+                id: magnet.id,
+                x: magnet.x,
+                y: magnet.y,
+                rotation: magnet.rotation,
+                z_index: magnet.z_index,
+            })
+        }
+        // Anything else, closing makes them reconnect and start over from
+        // what's actually on the board
+        Err(e) if session_state.protocol == Protocol::Legacy => return Err(e),
+        Err(e) => {
+            let Some(code) = e.to_error_code() else {
+                return Err(e);
+            };
+            tracing::debug!("Rejecting request: {e}");
+            MagnetUpdate::Error {
+                request_id,
+                id: magnet_id,
+                code,
+            }
+        }
+    };
+
+    send_update(
+        &mut session_state.ws_stream,
+        session_state.protocol,
+        &response,
+    )
+    .await?;
     Ok(())
 }
 
-#[tracing::instrument(skip(ws_stream, session_id))]
-async fn close_with(ws_stream: &mut WsStream, error: FridgeError, session_id: &Uuid) -> bool {
+#[tracing::instrument(skip(ws_stream, session_id, metrics))]
+async fn close_with<S: Socket>(
+    ws_stream: &mut WebSocketStream<S>,
+    error: FridgeError,
+    session_id: &Uuid,
+    metrics: &Metrics,
+) -> bool {
     sentry::configure_scope(|scope| scope.set_tag("session_id", session_id));
 
     match &error {
         e @ FridgeError::ClientClose(_) => {
             tracing::debug!("{e}");
+            metrics.record_disconnect(&error);
             return true;
         }
         e @ FridgeError::Other(_) | e @ FridgeError::Sqlx(_) => {
             tracing::error!("{e}");
         }
+        e @ FridgeError::Abusive => {
+            tracing::info!("{e}");
+        }
         _ => {}
     }
 
     if let Some(close_message) = error.to_close_message() {
+        metrics.record_disconnect(&error);
         tracing::debug!("Closing connection with {close_message:?}");
         let _ = ws_stream.send(close_message).await;
         return true;
@@ -291,58 +784,51 @@ async fn close_with(ws_stream: &mut WsStream, error: FridgeError, session_id: &U
     false
 }
 
-const REQUESTS_PER_SECOND: usize = 5;
+/// How long sessions wait on quiet clients.
+#[derive(Clone, Copy, Debug)]
+pub struct SessionTimeouts {
+    /// How long a client can go without sending anything before it's
+    /// disconnected
+    pub idle: Duration,
+    /// How often a quiet client is pinged
+    pub heartbeat: Duration,
+}
 
 #[derive(Debug)]
-struct SessionState {
+struct SessionState<S> {
     session_id: Uuid,
     span: tracing::Span,
 
-    ws_stream: WsStream,
+    ws_stream: WebSocketStream<S>,
+    protocol: Protocol,
+    resume_token: String,
 
-    rx: tokio::sync::broadcast::Receiver<PgMagnetUpdate>,
+    board: Arc<Board>,
+    rx: Subscription,
 
     client_window: Window,
+    undo_stack: VecDeque<MoveRecord>,
+    /// When the last drag was passed on
+    last_drag: Option<Instant>,
+    /// When the cursor was last passed on, and where it was
+    last_cursor: Option<(Instant, (i32, i32))>,
 
-    last_n_requests: [Option<Instant>; REQUESTS_PER_SECOND],
-    current_request_index: usize,
+    rate_limiter: SessionRateLimiter,
     time_since_last_comms: Instant,
 }
 
 #[tracing::instrument(skip(message, session_state))]
-async fn handle_websocket_message(
+async fn handle_websocket_message<S: Socket>(
     message: Option<Result<Message, tokio_websockets::Error>>,
     app_state: &AppState,
-    session_state: &mut SessionState,
+    session_state: &mut SessionState<S>,
 ) -> Result<(), FridgeError> {
     sentry::configure_scope(|scope| scope.set_tag("session_id", session_state.session_id));
 
-    let now = Instant::now();
-    if let Some(timestamp) = session_state.last_n_requests[session_state.current_request_index] {
-        if (now - timestamp) >= Duration::from_millis(1000) {
-            session_state.last_n_requests[session_state.current_request_index] = Some(now);
-        } else {
-            return Err(FridgeError::RateLimited);
-        }
-    } else {
-        session_state.last_n_requests[session_state.current_request_index] = Some(now);
-    }
-    session_state.current_request_index += 1;
-    if session_state.current_request_index > REQUESTS_PER_SECOND - 1 {
-        session_state.current_request_index = 0;
-    }
-
     match message {
         Some(Ok(message)) if message.is_binary() => {
-            session_state.time_since_last_comms = now;
-            handle_websocket_binary(
-                message.into_payload(),
-                &mut session_state.client_window,
-                &mut session_state.ws_stream,
-                app_state,
-                &session_state.session_id,
-            )
-            .await?;
+            session_state.time_since_last_comms = Instant::now();
+            handle_websocket_binary(message.into_payload(), session_state, app_state).await?;
         }
         Some(Ok(message)) if message.is_pong() => {
             let payload = message.into_payload();
@@ -378,9 +864,9 @@ async fn handle_websocket_message(
     Ok(())
 }
 
-async fn get_next_action(
+async fn get_next_action<S: Socket>(
     app_state: &AppState,
-    session_state: &mut SessionState,
+    session_state: &mut SessionState<S>,
 ) -> Result<(), FridgeError> {
     let session_span = session_state.span.clone();
 
@@ -389,17 +875,70 @@ async fn get_next_action(
             return Err(FridgeError::Shutdown);
         }
 
-        // Update to a magnet entity from Postgres
-        magnet_update = session_state.rx.recv() => {
-            let magnet_update = magnet_update.map_err(anyhow::Error::from)?;
-            send_relevant_update(
-                &mut session_state.ws_stream,
-                &session_state.client_window,
-                magnet_update,
-                &session_state.session_id
-            )
-            .instrument(session_span)
-            .await?;
+        // A change from storage, or someone on this server holding,
+        // dragging or pointing
+        board_event = session_state.rx.recv() => {
+            match board_event {
+                Ok(BoardEvent::Change(ChangeEvent::Update(magnet_update))) => {
+                    send_relevant_update(
+                        &mut session_state.ws_stream,
+                        session_state.protocol,
+                        &session_state.client_window,
+                        magnet_update,
+                        &session_state.session_id
+                    )
+                    .instrument(session_span)
+                    .await?;
+                }
+                Ok(BoardEvent::Hold(hold_update)) => {
+                    send_relevant_hold(
+                        &mut session_state.ws_stream,
+                        session_state.protocol,
+                        &session_state.client_window,
+                        hold_update,
+                        &session_state.session_id
+                    )
+                    .instrument(session_span)
+                    .await?;
+                }
+                Ok(BoardEvent::Drag(drag_update)) => {
+                    send_relevant_drag(
+                        &mut session_state.ws_stream,
+                        session_state.protocol,
+                        &session_state.client_window,
+                        drag_update,
+                        &session_state.session_id
+                    )
+                    .instrument(session_span)
+                    .await?;
+                }
+                Ok(BoardEvent::Cursor(cursor_update)) => {
+                    send_relevant_cursor(
+                        &mut session_state.ws_stream,
+                        session_state.protocol,
+                        &session_state.client_window,
+                        cursor_update,
+                        session_state.rx.id()
+                    )
+                    .instrument(session_span)
+                    .await?;
+                }
+                Ok(BoardEvent::Change(ChangeEvent::Gap)) => {
+                    tracing::debug!(parent: &session_span, "Change stream was interrupted, resynchronizing");
+                    refresh_window(session_state, app_state).instrument(session_span).await?;
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::debug!(
+                        parent: &session_span,
+                        "Lagged behind by {skipped} updates, resynchronizing"
+                    );
+                    app_state.metrics.record_lag(skipped);
+                    refresh_window(session_state, app_state).instrument(session_span).await?;
+                }
+                Err(e @ RecvError::Closed) => {
+                    return Err(anyhow::Error::from(e).into());
+                }
+            }
         }
 
         message = session_state.ws_stream.next() => {
@@ -410,50 +949,171 @@ async fn get_next_action(
     Ok(())
 }
 
-pub async fn handle_socket(mut ws_stream: WsStream, session_id: Uuid, app_state: AppState) {
-    let session_span = tracing::span!(Level::DEBUG, "session", id = session_id.to_string());
+/// Resends everything in the client's window, for when we can't tell which
+/// updates it has missed.
+#[tracing::instrument(skip(session_state, app_state))]
+async fn refresh_window<S: Socket>(
+    session_state: &mut SessionState<S>,
+    app_state: &AppState,
+) -> Result<(), FridgeError> {
+    if !session_state.client_window.is_valid() {
+        // Nothing to refresh before the client tells us what it's looking at
+        return Ok(());
+    }
+
+    send_new_magnets(
+        &mut session_state.ws_stream,
+        session_state.protocol,
+        &session_state.board.id,
+        &Shape::Window(session_state.client_window.clone()),
+        app_state.magnet_cache.as_deref(),
+        &*app_state.storage,
+        &app_state.metrics,
+    )
+    .await
+}
+
+#[tracing::instrument(skip(session_state, missed_updates, app_state))]
+async fn catch_up<S: Socket>(
+    session_state: &mut SessionState<S>,
+    missed_updates: Vec<PgMagnetUpdate>,
+    lagged: bool,
+    app_state: &AppState,
+) -> Result<(), FridgeError> {
+    if lagged {
+        tracing::debug!("Missed too many updates while disconnected, refreshing window");
+        return refresh_window(session_state, app_state).await;
+    }
+
+    tracing::debug!("Replaying {} missed updates", missed_updates.len());
+    for magnet_update in missed_updates {
+        send_relevant_update(
+            &mut session_state.ws_stream,
+            session_state.protocol,
+            &session_state.client_window,
+            magnet_update,
+            &session_state.session_id,
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn handle_socket<S: Socket>(
+    mut ws_stream: WebSocketStream<S>,
+    protocol: Protocol,
+    board: Arc<Board>,
+    session_id: Uuid,
+    peer_addr: IpAddr,
+    resumed: Option<ResumedSession>,
+    app_state: AppState,
+) {
+    let session_span = tracing::span!(
+        Level::DEBUG,
+        "session",
+        id = session_id.to_string(),
+        peer = %peer_addr
+    );
+    let resume_token = resume::new_resume_token();
 
     {
-        let session_id_update = MagnetUpdate::SessionIdUpdate(session_id.to_string());
-        let buf = rmp_serde::to_vec(&session_id_update).unwrap();
-        if ws_stream.send(Message::binary(buf)).await.is_err() {
+        let session_id_update = MagnetUpdate::SessionIdUpdate {
+            session_id: session_id.to_string(),
+            resume_token: resume_token.clone(),
+            resumed: resumed.is_some(),
+        };
+        if send_update(&mut ws_stream, protocol, &session_id_update)
+            .await
+            .is_err()
+        {
             tracing::debug!(parent: &session_span, "Unable to establish connnection");
             return;
         }
     }
+    let _active_session = app_state.metrics.session_started();
+
+    let session = resumed.unwrap_or_else(|| {
+        ResumedSession::new(
+            board.clone(),
+            board.subscriptions.subscribe(),
+            Window::default(),
+            VecDeque::new(),
+        )
+    });
 
     let mut session_state = SessionState {
         session_id,
         span: session_span,
         ws_stream,
-        rx: app_state.magnet_updates.subscribe(),
-        client_window: Window::default(),
-        last_n_requests: [None; REQUESTS_PER_SECOND],
-        current_request_index: 0,
+        protocol,
+        resume_token,
+        board,
+        rx: session.rx,
+        client_window: session.client_window,
+        undo_stack: session.undo_stack,
+        last_drag: None,
+        last_cursor: None,
+        rate_limiter: app_state.rate_limiter.session(peer_addr),
         time_since_last_comms: Instant::now(),
     };
 
-    const MAX_IDLE_TIME: Duration = Duration::from_secs(300);
-    const TEN_SECS: Duration = Duration::from_millis(10000);
+    let session_span = session_state.span.clone();
+    if let Err(e) = catch_up(
+        &mut session_state,
+        session.missed_updates,
+        session.lagged,
+        &app_state,
+    )
+    .instrument(session_span)
+    .await
+    {
+        close_with(
+            &mut session_state.ws_stream,
+            e,
+            &session_state.session_id,
+            &app_state.metrics,
+        )
+        .instrument(session_state.span.clone())
+        .await;
+        return;
+    }
 
+    // Whether the client should be able to pick this session back up
+    let mut resumable = true;
     loop {
-        match timeout(TEN_SECS, get_next_action(&app_state, &mut session_state)).await {
+        match timeout(
+            app_state.session_timeouts.heartbeat,
+            get_next_action(&app_state, &mut session_state),
+        )
+        .await
+        {
             Ok(Ok(())) => {}
             Ok(Err(e)) => {
-                if close_with(&mut session_state.ws_stream, e, &session_state.session_id)
-                    .instrument(session_state.span.clone())
-                    .await
+                resumable = !matches!(e, FridgeError::Shutdown);
+                if close_with(
+                    &mut session_state.ws_stream,
+                    e,
+                    &session_state.session_id,
+                    &app_state.metrics,
+                )
+                .instrument(session_state.span.clone())
+                .await
                 {
                     break;
                 }
             }
             Err(_) => {
-                if (Instant::now() - session_state.time_since_last_comms) > MAX_IDLE_TIME {
+                if (Instant::now() - session_state.time_since_last_comms)
+                    > app_state.session_timeouts.idle
+                {
                     tracing::trace!(parent: &session_state.span, "Exceeded max idle time");
+                    resumable = false;
                     close_with(
                         &mut session_state.ws_stream,
                         FridgeError::IdleTimeout,
                         &session_state.session_id,
+                        &app_state.metrics,
                     )
                     .instrument(session_state.span.clone())
                     .await;
@@ -466,6 +1126,7 @@ pub async fn handle_socket(mut ws_stream: WsStream, session_id: Uuid, app_state:
                         &mut session_state.ws_stream,
                         FridgeError::Tungstenite(e),
                         &session_state.session_id,
+                        &app_state.metrics,
                     )
                     .instrument(session_state.span.clone())
                     .await;
@@ -474,4 +1135,39 @@ pub async fn handle_socket(mut ws_stream: WsStream, session_id: Uuid, app_state:
             }
         }
     }
+
+    // Nobody else should have to wait for the leases to run out
+    for hold_update in session_state
+        .board
+        .leases
+        .release_all(&session_state.session_id)
+    {
+        session_state.board.subscriptions.publish_hold(hold_update);
+    }
+
+    if let Some((_, last_position)) = session_state.last_cursor {
+        session_state
+            .board
+            .subscriptions
+            .publish_cursor(CursorUpdate {
+                viewer: session_state.rx.id(),
+                old: Some(last_position),
+                new: None,
+            });
+    }
+
+    // Legacy clients never learn their resume token
+    if resumable && session_state.protocol == Protocol::V2 {
+        app_state.suspended_sessions.suspend(
+            session_state.session_id,
+            session_state.resume_token,
+            ResumedSession::new(
+                session_state.board,
+                session_state.rx,
+                session_state.client_window,
+                session_state.undo_stack,
+            ),
+            app_state.token.clone(),
+        );
+    }
 }
@@ -0,0 +1,600 @@
+use std::{net::IpAddr, sync::Arc, time::Duration};
+
+use futures_util::{SinkExt as _, StreamExt as _};
+use serde_json::{Value, json};
+use tokio::{io::DuplexStream, task::JoinHandle};
+use tokio_websockets::{ClientBuilder, Message, ServerBuilder, WebSocketStream};
+use uuid::Uuid;
+
+use super::handle_socket;
+use crate::{
+    board::{Board, Boards, DEFAULT_BOARD},
+    config::Config,
+    metrics::Metrics,
+    protocol::Protocol,
+    rate_limit::RateLimiter,
+    resume::ResumeRequest,
+    state::AppState,
+    storage::{MemoryStorage, Storage},
+};
+
+/// How long to wait for something the server should send right away.
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A server running on in-memory storage, with sessions connected over pipes
+/// instead of sockets.
+struct Harness {
+    state: AppState,
+    storage: Arc<MemoryStorage>,
+    board: Arc<Board>,
+}
+
+impl Harness {
+    /// `capacity` is how many updates a session can have queued before it's
+    /// considered to be lagging.
+    async fn new(capacity: usize) -> Harness {
+        // Tests send requests faster than anyone would by hand
+        let config = Config {
+            window_updates_per_second: Some(1000.0),
+            moves_per_second: Some(1000.0),
+            ..Config::default()
+        };
+        let storage = Arc::new(MemoryStorage::new(1024));
+        let metrics = Arc::new(Metrics::default());
+        let boards = Boards::new(capacity);
+        let token = tokio_util::sync::CancellationToken::new();
+
+        let changes = storage.changes(token.clone()).await.unwrap();
+        let broadcast_changes = tokio::spawn(crate::broadcast_changes(
+            boards.clone(),
+            None,
+            storage.clone(),
+            metrics.clone(),
+            changes,
+        ));
+
+        let state = AppState {
+            storage: storage.clone(),
+            boards,
+            magnet_cache: None,
+            token,
+            suspended_sessions: Default::default(),
+            rate_limiter: Arc::new(RateLimiter::new(config.rate_limits())),
+            metrics,
+            allowed_origins: Default::default(),
+            trusted_proxies: Default::default(),
+            broadcast_changes: broadcast_changes.abort_handle(),
+            session_timeouts: config.session_timeouts(),
+            history_retention: config.history_retention(),
+            tls: None,
+        };
+        let board = state
+            .boards
+            .get(DEFAULT_BOARD, &*state.storage)
+            .await
+            .unwrap()
+            .unwrap();
+
+        Harness {
+            state,
+            storage,
+            board,
+        }
+    }
+
+    fn add_magnet(&self, word: &str, x: i32, y: i32) -> i32 {
+        self.storage
+            .add_magnet(DEFAULT_BOARD, x, y, 0, word.to_string())
+    }
+
+    /// Opens a new session, and waits for it to say hello.
+    async fn connect(&self, protocol: Protocol) -> (Client, Value) {
+        self.open(protocol, Uuid::now_v7(), None).await
+    }
+
+    /// Picks a suspended session back up the way `accept_connection` does.
+    async fn resume(&self, session_id: Uuid, resume_token: &str) -> (Client, Value) {
+        let resumed = self
+            .state
+            .suspended_sessions
+            .resume(
+                DEFAULT_BOARD,
+                &ResumeRequest {
+                    session_id,
+                    resume_token: resume_token.to_string(),
+                },
+            )
+            .await;
+        assert!(resumed.is_some(), "Session {session_id} can't be resumed");
+        self.open(Protocol::V2, session_id, resumed).await
+    }
+
+    async fn open(
+        &self,
+        protocol: Protocol,
+        session_id: Uuid,
+        resumed: Option<crate::resume::ResumedSession>,
+    ) -> (Client, Value) {
+        let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+        let session = tokio::spawn(handle_socket(
+            ServerBuilder::new().serve(server_io),
+            protocol,
+            self.board.clone(),
+            session_id,
+            IpAddr::from([127, 0, 0, 1]),
+            resumed,
+            self.state.clone(),
+        ));
+
+        let mut client = Client {
+            ws: ClientBuilder::new().take_over(client_io),
+            protocol,
+            session,
+        };
+        let hello = client.recv().await;
+        (client, hello)
+    }
+}
+
+/// The client end of a session.
+struct Client {
+    ws: WebSocketStream<DuplexStream>,
+    protocol: Protocol,
+    session: JoinHandle<()>,
+}
+
+impl Client {
+    /// Sends a request, given as it would be written in JSON. Legacy requests
+    /// are arrays, the way the frontend packs them.
+    async fn send(&mut self, request: Value) {
+        assert!(
+            self.protocol == Protocol::V2 || request.is_array(),
+            "Legacy clients only send arrays, not {request}"
+        );
+        self.ws
+            .send(Message::binary(rmp_serde::to_vec(&request).unwrap()))
+            .await
+            .unwrap();
+    }
+
+    /// Waits for the next update, as it would be written in JSON.
+    async fn recv(&mut self) -> Value {
+        let message = tokio::time::timeout(RECV_TIMEOUT, self.ws.next())
+            .await
+            .expect("Timed out waiting for an update")
+            .expect("Connection closed")
+            .unwrap();
+        assert!(message.is_binary(), "Expected an update, got {message:?}");
+        rmp_serde::from_slice(&message.into_payload()).unwrap()
+    }
+
+    /// Skips updates until one of the `kind` comes along.
+    async fn recv_kind(&mut self, kind: &str) -> Value {
+        loop {
+            let update = self.recv().await;
+            if update["type"] == kind {
+                return update;
+            }
+        }
+    }
+
+    /// Sets the window and waits for the magnets in it.
+    async fn look_at(&mut self, x1: i32, y1: i32, x2: i32, y2: i32) -> Vec<Value> {
+        self.send(json!({ "type": "window", "x1": x1, "y1": y1, "x2": x2, "y2": y2 }))
+            .await;
+        let canvas = self.recv_kind("canvas_update").await;
+        canvas["magnets"].as_array().unwrap().clone()
+    }
+
+    /// Hangs up, and waits for the server to be done with the session.
+    async fn disconnect(mut self) {
+        self.ws.close().await.unwrap();
+        drop(self.ws);
+        tokio::time::timeout(RECV_TIMEOUT, self.session)
+            .await
+            .expect("Session didn't end")
+            .unwrap();
+    }
+}
+
+fn position(magnet: &Value) -> (i64, i64) {
+    (magnet["x"].as_i64().unwrap(), magnet["y"].as_i64().unwrap())
+}
+
+#[tokio::test]
+async fn moves_reach_other_sessions_looking_at_the_magnet() {
+    let harness = Harness::new(16).await;
+    let id = harness.add_magnet("fridge", 10, 10);
+
+    let (mut mover, _) = harness.connect(Protocol::V2).await;
+    let (mut viewer, _) = harness.connect(Protocol::V2).await;
+    let (mut elsewhere, _) = harness.connect(Protocol::V2).await;
+    assert_eq!(mover.look_at(0, 0, 1000, 1000).await.len(), 1);
+    assert_eq!(viewer.look_at(0, 0, 1000, 1000).await.len(), 1);
+    assert!(
+        elsewhere
+            .look_at(10_000, 10_000, 11_000, 11_000)
+            .await
+            .is_empty()
+    );
+
+    mover
+        .send(
+            json!({ "type": "magnet", "request_id": 7, "id": id, "x": 20, "y": 30, "rotation": 5 }),
+        )
+        .await;
+    let ack = mover.recv_kind("ack").await;
+    assert_eq!(ack["request_id"], 7);
+    assert!(ack["z_index"].is_i64());
+
+    let moved = viewer.recv_kind("move").await;
+    assert_eq!(moved["id"], id);
+    assert_eq!(position(&moved), (20, 30));
+    assert_eq!(moved["rotation"], 5);
+    assert_eq!(moved["z_index"], ack["z_index"]);
+
+    // Nothing should have been queued for a session on another tile
+    elsewhere.send(json!({ "type": "viewers" })).await;
+    assert_eq!(elsewhere.recv().await["type"], "viewers");
+}
+
+#[tokio::test]
+async fn legacy_sessions_move_magnets_in_the_original_format() {
+    let harness = Harness::new(16).await;
+    let id = harness.add_magnet("fridge", 10, 10);
+
+    let (mut legacy, hello) = harness.connect(Protocol::Legacy).await;
+    assert!(hello.is_string(), "Expected a bare session id, got {hello}");
+
+    legacy.send(json!([0, 0, 1000, 1000])).await;
+    let canvas = legacy.recv().await;
+    assert_eq!(canvas[0][0], id);
+
+    // Moves go as [is_magnet_update, id, x, y, rotation]
+    legacy.send(json!([true, id, 20, 30, 0])).await;
+    // Moves come back as [id, x, y, rotation, z_index]
+    let moved = legacy.recv().await;
+    assert_eq!(
+        (&moved[0], &moved[1], &moved[2]),
+        (&json!(id), &json!(20), &json!(30))
+    );
+}
+
+#[tokio::test]
+async fn resumed_sessions_catch_up_on_missed_moves() {
+    let harness = Harness::new(16).await;
+    let id = harness.add_magnet("fridge", 10, 10);
+
+    let (mut client, hello) = harness.connect(Protocol::V2).await;
+    assert_eq!(hello["resumed"], false);
+    let session_id: Uuid = hello["session_id"].as_str().unwrap().parse().unwrap();
+    let resume_token = hello["resume_token"].as_str().unwrap().to_string();
+    client.look_at(0, 0, 1000, 1000).await;
+    client.disconnect().await;
+
+    // Someone else moves the magnet while the client is away
+    let (mut other, _) = harness.connect(Protocol::V2).await;
+    other.look_at(0, 0, 1000, 1000).await;
+    other
+        .send(
+            json!({ "type": "magnet", "request_id": 1, "id": id, "x": 40, "y": 50, "rotation": 0 }),
+        )
+        .await;
+    other.recv_kind("move").await;
+
+    let (mut client, hello) = harness.resume(session_id, &resume_token).await;
+    assert_eq!(hello["resumed"], true);
+    assert_eq!(hello["session_id"], session_id.to_string());
+
+    let moved = client.recv().await;
+    assert_eq!(moved["type"], "move");
+    assert_eq!(position(&moved), (40, 50));
+
+    // The window carried over, so later moves arrive without asking again
+    other
+        .send(
+            json!({ "type": "magnet", "request_id": 2, "id": id, "x": 60, "y": 70, "rotation": 0 }),
+        )
+        .await;
+    assert_eq!(position(&client.recv_kind("move").await), (60, 70));
+}
+
+#[tokio::test]
+async fn sessions_cant_be_resumed_with_the_wrong_token() {
+    let harness = Harness::new(16).await;
+
+    let (client, hello) = harness.connect(Protocol::V2).await;
+    let session_id: Uuid = hello["session_id"].as_str().unwrap().parse().unwrap();
+    client.disconnect().await;
+
+    let resumed = harness
+        .state
+        .suspended_sessions
+        .resume(
+            DEFAULT_BOARD,
+            &ResumeRequest {
+                session_id,
+                resume_token: "not the token".to_string(),
+            },
+        )
+        .await;
+    assert!(resumed.is_none());
+}
+
+#[tokio::test]
+async fn sessions_survive_attempts_to_resume_them_on_other_boards() {
+    let harness = Harness::new(16).await;
+
+    let (client, hello) = harness.connect(Protocol::V2).await;
+    let session_id: Uuid = hello["session_id"].as_str().unwrap().parse().unwrap();
+    let resume_token = hello["resume_token"].as_str().unwrap().to_string();
+    client.disconnect().await;
+
+    let resumed = harness
+        .state
+        .suspended_sessions
+        .resume(
+            "elsewhere",
+            &ResumeRequest {
+                session_id,
+                resume_token: resume_token.clone(),
+            },
+        )
+        .await;
+    assert!(resumed.is_none());
+
+    harness.resume(session_id, &resume_token).await;
+}
+
+#[tokio::test]
+async fn lagging_sessions_are_resynced_with_the_whole_window() {
+    // Room for one queued update, so that a burst of moves overflows it
+    let harness = Harness::new(1).await;
+    let id = harness.add_magnet("fridge", 10, 10);
+
+    let (mut client, _) = harness.connect(Protocol::V2).await;
+    client.look_at(0, 0, 1000, 1000).await;
+
+    // Moved straight through storage, without giving the session a chance to
+    // keep up in between
+    let session_id = Uuid::now_v7();
+    for x in 100..110 {
+        let update = crate::protocol::ClientMagnetUpdate {
+            id,
+            x,
+            y: 10,
+            rotation: 0,
+            z_index: None,
+        };
+        harness
+            .storage
+            .move_magnet(DEFAULT_BOARD, &update, &session_id)
+            .await
+            .unwrap();
+    }
+
+    let canvas = client.recv_kind("canvas_update").await;
+    let magnets = canvas["magnets"].as_array().unwrap();
+    assert_eq!(magnets.len(), 1);
+    assert_eq!(position(&magnets[0]), (109, 10));
+    assert_eq!(
+        harness
+            .state
+            .metrics
+            .lagged_sessions
+            .load(std::sync::atomic::Ordering::Relaxed),
+        1
+    );
+}
+
+#[tokio::test]
+async fn history_is_only_kept_for_so_long() {
+    let harness = Harness::new(16).await;
+    harness.add_magnet("fridge", 10, 10);
+
+    let (mut client, _) = harness.connect(Protocol::V2).await;
+    client.look_at(0, 0, 1000, 1000).await;
+
+    let now = sqlx::types::chrono::Utc::now().timestamp_millis();
+    client
+        .send(json!({ "type": "history", "request_id": 1, "at": now - 1000 }))
+        .await;
+    let history = client.recv().await;
+    assert_eq!(history["type"], "history_update");
+    assert_eq!(history["magnets"].as_array().unwrap().len(), 1);
+
+    client
+        .send(json!({ "type": "history", "request_id": 2, "at": 0 }))
+        .await;
+    let error = client.recv_kind("error").await;
+    assert_eq!(error["request_id"], 2);
+    assert_eq!(error["code"], "out_of_bounds");
+}
+
+#[tokio::test]
+async fn grabs_are_heard_where_the_magnet_really_is() {
+    let harness = Harness::new(16).await;
+    let near = harness.add_magnet("near", 10, 10);
+    let far = harness.add_magnet("far", 5000, 5000);
+
+    let (mut holder, _) = harness.connect(Protocol::V2).await;
+    let (mut viewer, _) = harness.connect(Protocol::V2).await;
+    holder.look_at(0, 0, 1000, 1000).await;
+    viewer.look_at(0, 0, 1000, 1000).await;
+
+    // Wherever the client says the magnet is, it has to be in the window
+    holder
+        .send(json!({ "type": "grab", "request_id": 1, "id": far, "x": 10, "y": 10 }))
+        .await;
+    let error = holder.recv_kind("error").await;
+    assert_eq!(error["code"], "out_of_bounds");
+
+    holder
+        .send(json!({ "type": "grab", "request_id": 2, "id": near }))
+        .await;
+    assert_eq!(holder.recv_kind("ack").await["request_id"], 2);
+    let held = viewer.recv_kind("held").await;
+    assert_eq!(held["id"], near);
+
+    viewer
+        .send(json!({ "type": "grab", "request_id": 3, "id": near }))
+        .await;
+    assert_eq!(viewer.recv_kind("error").await["code"], "held");
+}
+
+#[tokio::test]
+async fn sessions_can_only_hold_so_many_magnets() {
+    let harness = Harness::new(16).await;
+    let ids: Vec<i32> = (0..6)
+        .map(|i| harness.add_magnet("word", 10 + i * 50, 10))
+        .collect();
+
+    let (mut holder, _) = harness.connect(Protocol::V2).await;
+    holder.look_at(0, 0, 1000, 1000).await;
+
+    for (request_id, id) in ids[..5].iter().enumerate() {
+        holder
+            .send(json!({ "type": "grab", "request_id": request_id, "id": id }))
+            .await;
+        assert_eq!(holder.recv().await["type"], "ack");
+    }
+
+    holder
+        .send(json!({ "type": "grab", "request_id": 5, "id": ids[5] }))
+        .await;
+    assert_eq!(holder.recv().await["code"], "too_many_held");
+
+    // Held magnets can still be renewed, and let go of to make room
+    holder
+        .send(json!({ "type": "grab", "request_id": 6, "id": ids[0] }))
+        .await;
+    assert_eq!(holder.recv().await["type"], "ack");
+    holder
+        .send(json!({ "type": "release", "request_id": 7, "id": ids[0] }))
+        .await;
+    assert_eq!(holder.recv().await["type"], "ack");
+    holder
+        .send(json!({ "type": "grab", "request_id": 8, "id": ids[5] }))
+        .await;
+    assert_eq!(holder.recv().await["type"], "ack");
+}
+
+#[tokio::test]
+async fn legacy_moves_of_held_magnets_are_put_back() {
+    let harness = Harness::new(16).await;
+    let id = harness.add_magnet("fridge", 10, 10);
+
+    let (mut holder, _) = harness.connect(Protocol::V2).await;
+    holder.look_at(0, 0, 1000, 1000).await;
+    holder
+        .send(json!({ "type": "grab", "request_id": 1, "id": id }))
+        .await;
+    holder.recv_kind("ack").await;
+
+    let (mut legacy, _) = harness.connect(Protocol::Legacy).await;
+    legacy.send(json!([0, 0, 1000, 1000])).await;
+    legacy.recv().await;
+
+    legacy.send(json!([true, id, 20, 30, 0])).await;
+    let moved = legacy.recv().await;
+    assert_eq!(
+        (&moved[0], &moved[1], &moved[2]),
+        (&json!(id), &json!(10), &json!(10))
+    );
+
+    // Still connected
+    legacy.send(json!([0, 0, 500, 500])).await;
+    assert_eq!(legacy.recv().await[0][0], id);
+}
+
+#[tokio::test]
+async fn undos_say_how_many_magnets_were_put_back() {
+    let harness = Harness::new(16).await;
+    let free = harness.add_magnet("fridge", 10, 10);
+    let held = harness.add_magnet("poetry", 50, 50);
+
+    let (mut mover, _) = harness.connect(Protocol::V2).await;
+    let (mut holder, _) = harness.connect(Protocol::V2).await;
+    mover.look_at(0, 0, 1000, 1000).await;
+    holder.look_at(0, 0, 1000, 1000).await;
+
+    for (request_id, id) in [(1, free), (2, held)] {
+        mover
+            .send(json!({
+                "type": "magnet", "request_id": request_id, "id": id, "x": 100, "y": 100,
+                "rotation": 0
+            }))
+            .await;
+        mover.recv_kind("ack").await;
+    }
+
+    holder
+        .send(json!({ "type": "grab", "request_id": 1, "id": held }))
+        .await;
+    holder.recv_kind("ack").await;
+
+    mover
+        .send(json!({ "type": "undo", "request_id": 3, "count": 2 }))
+        .await;
+    let ack = mover.recv_kind("ack").await;
+    assert_eq!(ack["request_id"], 3);
+    assert_eq!(ack["reverted"], 1);
+}
+
+#[tokio::test]
+async fn only_the_holder_can_drag_a_magnet() {
+    let harness = Harness::new(16).await;
+    let id = harness.add_magnet("fridge", 10, 10);
+
+    let (mut holder, _) = harness.connect(Protocol::V2).await;
+    let (mut viewer, _) = harness.connect(Protocol::V2).await;
+    holder.look_at(0, 0, 1000, 1000).await;
+    viewer.look_at(0, 0, 1000, 1000).await;
+
+    holder
+        .send(json!({ "type": "drag", "request_id": 1, "id": id, "x": 20, "y": 20, "rotation": 0 }))
+        .await;
+    assert_eq!(holder.recv_kind("error").await["code"], "not_held");
+
+    holder
+        .send(json!({ "type": "grab", "request_id": 2, "id": id }))
+        .await;
+    holder.recv_kind("ack").await;
+    viewer.recv_kind("held").await;
+
+    viewer
+        .send(json!({ "type": "drag", "request_id": 3, "id": id, "x": 30, "y": 30, "rotation": 0 }))
+        .await;
+    assert_eq!(viewer.recv_kind("error").await["code"], "held");
+
+    tokio::time::sleep(super::DRAG_INTERVAL).await;
+    holder
+        .send(json!({ "type": "drag", "request_id": 4, "id": id, "x": 40, "y": 40, "rotation": 0 }))
+        .await;
+    holder.recv_kind("ack").await;
+    let dragged = viewer.recv_kind("dragged").await;
+    assert_eq!(position(&dragged), (40, 40));
+}
+
+#[tokio::test]
+async fn suspended_sessions_are_not_counted_as_viewers() {
+    let harness = Harness::new(16).await;
+
+    let (mut counter, _) = harness.connect(Protocol::V2).await;
+    let (mut viewer, hello) = harness.connect(Protocol::V2).await;
+    counter.look_at(0, 0, 1000, 1000).await;
+    viewer.look_at(0, 0, 1000, 1000).await;
+
+    counter.send(json!({ "type": "viewers" })).await;
+    assert_eq!(counter.recv_kind("viewers").await["count"], 1);
+
+    viewer.disconnect().await;
+    counter.send(json!({ "type": "viewers" })).await;
+    assert_eq!(counter.recv_kind("viewers").await["count"], 0);
+
+    let session_id: Uuid = hello["session_id"].as_str().unwrap().parse().unwrap();
+    let resume_token = hello["resume_token"].as_str().unwrap();
+    let (_viewer, _) = harness.resume(session_id, resume_token).await;
+    counter.send(json!({ "type": "viewers" })).await;
+    assert_eq!(counter.recv_kind("viewers").await["count"], 1);
+}